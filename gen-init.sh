@@ -20,12 +20,40 @@ cp /lib64/ld-linux-x86-64.so.2 "$DIR"/lib64
 # We also need a statically linked busybox
 cp $(which busybox) "$DIR"/bin/busybox
 
+# Copies a binary to sbin, along with the libraries ldd lists if it's dynamically linked, to the same paths in the
+# image. A static binary has nothing listed
+copy_bin() {
+  cp "$(which "$1")" "$DIR"/sbin/"$1"
+  for lib in $(ldd "$(which "$1")" 2> /dev/null | grep -o '/[^ ]*'); do
+    mkdir -p "$DIR$(dirname "$lib")"
+    cp -L "$lib" "$DIR$lib"
+  done
+}
+
 # We need cryptsetup for Luks decrypt
-cp $(which cryptsetup) "$DIR"/sbin/cryptsetup
+copy_bin cryptsetup
+# And lvm if any volume is an LVM logical volume
+if command -v lvm > /dev/null; then
+  copy_bin lvm
+fi
+# And mdadm for software RAID
+if command -v mdadm > /dev/null; then
+  copy_bin mdadm
+fi
+BINARY=target/x86_64-unknown-linux-gnu/lto/yubi-initramfs
+# Detached LUKS headers, given as header=<path> crypt options in the cfg or its crypttab, go to the same path in
+# the image. The binary parses the cfg the way the init does, so commented out lines aren't picked up
+CFG=${CFG:-initramfs.cfg}
+if [ -f "$CFG" ]; then
+  HEADERS=$("$BINARY" "$CFG" --headers)
+  for header in $HEADERS; do
+    mkdir -p "$DIR$(dirname "$header")"
+    cp "$header" "$DIR$header"
+  done
+fi
 # We need to proxy blkid into sbin
 echo "#!/bin/busybox sh
 blkid" > "$DIR"/sbin/blkid && chmod +x "$DIR"/sbin/blkid
-BINARY=target/x86_64-unknown-linux-gnu/lto/yubi-initramfs
 # Build the file without any target cpu
 # RUSTFLAGS='-C panic=abort -C link-arg=-nostartfiles' cargo b -p yubi-initramfs --target x86_64-unknown-linux-gnu --profile lto
 # Name the binary the init default (which is just init)
@@ -0,0 +1,1236 @@
+//! The `initramfs.cfg` format.
+//!
+//! `key=value` lines, `//` starts a comment. Volumes can be given through the original shorthands
+//! `root=<uuid>`, `swap=<uuid>` and `home=<uuid>`, imported from an `fstab` and `crypttab` copied
+//! into the image (`fstab=/fstab`, `crypttab=/crypttab`), or spelled out per entry as
+//! `<md|crypt|mount|swap|zram>.<name>.<field>=<value>`. Entries are merged by name, later sources override
+//! single fields of earlier ones in the order tabs, shorthands, entries.
+//! ```text
+//! key_file_path=/crypto_keyfile.txt
+//! unlock=native
+//! pseudo_fs=move
+//! crypttab=/crypttab
+//! fstab=/fstab
+//! md.mirror.uuid=3b1a4e6c:8d2f5a90:1c7e4b3d:9f0a2e6b
+//! md.mirror.degraded=start
+//! crypt.chome.key_file=/home.key
+//! crypt.cdata.key_file=/etc/keys/data.key
+//! crypt.cdata.key_mount=root
+//! mount.home.options=noatime
+//! mount.data.source=vg0/data
+//! mount.data.target=/data
+//! swap.swapfile.source=/swapfile
+//! swap.swapfile.mount=root
+//! swap.swapfile.resume_offset=34816
+//! zram.zram0.size=50%
+//! zram.zram0.algorithm=zstd
+//! ```
+use crate::crypt_opts::parse_crypt_options;
+use crate::error::{Error, Result};
+use crate::lvm::{lv_from_device, mapper_name, parse_lv};
+use crate::md::is_md_uuid;
+use crate::mount_opts::parse_mount_options;
+use crate::plan::plan;
+use crate::swap::parse_swap_options;
+use crate::tab::{parse_crypttab, parse_fstab, CrypttabEntry, FstabEntry};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    /// Key for volumes that don't specify their own
+    pub key_file_path: Option<String>,
+    /// How volumes that don't specify their own are unlocked
+    pub unlock: Unlock,
+    /// What happens to `/dev`, `/proc` and `/sys` before switching root
+    pub pseudo_fs: PseudoFs,
+    pub md: Vec<MdArray>,
+    pub crypt: Vec<CryptVolume>,
+    pub mounts: Vec<Mount>,
+    pub swaps: Vec<Swap>,
+    pub zram: Vec<Zram>,
+}
+
+/// A software RAID array, assembled at `/dev/md/<name>` before unlocking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdArray {
+    pub name: String,
+    pub uuid: String,
+    pub degraded: DegradedPolicy,
+    /// How long to wait for all members before applying [`MdArray::degraded`]
+    pub timeout_secs: u64,
+}
+
+/// What to do with an array that's missing members.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DegradedPolicy {
+    Fail,
+    Start,
+}
+
+/// How a LUKS container is opened.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Unlock {
+    /// `/sbin/cryptsetup open`
+    Cryptsetup,
+    /// [`crate::unlock`], no cryptsetup needed in the image
+    Native,
+}
+
+/// What the pseudo filesystems the initramfs mounted are left as for the real init.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PseudoFs {
+    /// Unmounted, the real init mounts its own
+    Unmount,
+    /// Moved into the new root along with a fresh `/run` tmpfs, as systemd expects, see
+    /// [`crate::move_pseudo_filesystems`]
+    Move,
+}
+
+/// A LUKS container, opened at `/dev/mapper/<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CryptVolume {
+    pub name: String,
+    /// The LUKS uuid, or the partition uuid if the header is detached
+    pub uuid: String,
+    /// Path in the image, falls back to [`Cfg::key_file_path`]
+    pub key_file: Option<String>,
+    /// A [`Mount`] the key file is read from once mounted, `key_file` is then a path in the
+    /// booted system
+    pub key_mount: Option<String>,
+    /// crypttab style options
+    pub options: Vec<String>,
+    /// Falls back to [`Cfg::unlock`]
+    pub unlock: Option<Unlock>,
+    /// Expected hash of the detached header given by the `header=` option, hex encoded
+    pub header_sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub name: String,
+    /// A [`CryptVolume`] name, a `vg/lv` or a device path
+    pub source: String,
+    /// Relative to the new root, `/` for the root itself
+    pub target: String,
+    /// Detected from the superblock if not specified or `auto`
+    pub fs_type: Option<String>,
+    /// fstab style options, see [`crate::mount_opts`]
+    pub options: Vec<String>,
+    /// btrfs subvolume, several mounts can use different subvolumes of the same source
+    pub subvol: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Swap {
+    pub name: String,
+    /// A [`CryptVolume`] name, a `vg/lv` or a device path, or the path of a swap file in the booted
+    /// system
+    pub source: String,
+    /// fstab style options, see [`crate::swap`]
+    pub options: Vec<String>,
+    /// The [`Mount`] a swap file is on
+    pub mount: Option<String>,
+    /// Page offset of a swap file on its mount's device, to resume from a hibernation image in it
+    pub resume_offset: Option<u64>,
+}
+
+/// Compressed swap in RAM, created through `/sys/class/zram-control`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zram {
+    pub name: String,
+    pub size: ZramSize,
+    /// Kernel default if not specified
+    pub algorithm: Option<String>,
+    /// Swap priority, 0 to 32767, the kernel picks one if not specified
+    pub priority: Option<u16>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZramSize {
+    Bytes(u64),
+    /// Of `MemTotal`
+    Percent(u32),
+}
+
+impl Cfg {
+    /// Volume groups that need activating for the configured mounts and swaps.
+    pub fn volume_groups(&self) -> Vec<&str> {
+        let mut vgs = Vec::new();
+        let sources = self.mounts.iter().map(|m| m.source.as_str());
+        for source in sources.chain(self.swaps.iter().map(|s| s.source.as_str())) {
+            if let Some((vg, _)) = parse_lv(source) {
+                if !vgs.contains(&vg) {
+                    vgs.push(vg);
+                }
+            }
+        }
+        vgs
+    }
+
+    pub fn crypt_volume(&self, name: &str) -> Option<&CryptVolume> {
+        self.crypt.iter().find(|c| c.name == name)
+    }
+
+    /// The key file a volume is unlocked with.
+    pub fn key_file_for<'a>(&'a self, volume: &'a CryptVolume) -> Option<&'a str> {
+        volume.key_file.as_deref().or(self.key_file_path.as_deref())
+    }
+
+    /// Where the key file is read from while booting, under the new root if it's on a mount.
+    pub fn key_path_for(&self, volume: &CryptVolume) -> Option<String> {
+        let key_file = self.key_file_for(volume)?;
+        Some(match volume.key_mount {
+            Some(_) => target_path(key_file),
+            None => key_file.to_string(),
+        })
+    }
+
+    pub fn unlock_for(&self, volume: &CryptVolume) -> Unlock {
+        volume.unlock.unwrap_or(self.unlock)
+    }
+
+    /// Mounts ordered so that parents are mounted before their children.
+    pub fn mounts_in_order(&self) -> Vec<&Mount> {
+        let mut mounts: Vec<&Mount> = self.mounts.iter().collect();
+        mounts.sort_by_key(|m| m.target.split('/').filter(|c| !c.is_empty()).count());
+        mounts
+    }
+}
+
+/// Where a mount or swap source shows up once unlocked.
+pub fn source_device(source: &str) -> String {
+    if source.starts_with('/') {
+        source.to_string()
+    } else if let Some((vg, lv)) = parse_lv(source) {
+        format!("/dev/mapper/{}", mapper_name(vg, lv))
+    } else {
+        format!("/dev/mapper/{source}")
+    }
+}
+
+/// Where a mount target ends up before switching root.
+pub fn target_path(target: &str) -> String {
+    if target == "/" {
+        "/mnt/root".to_string()
+    } else {
+        format!("/mnt/root{target}")
+    }
+}
+
+pub fn read_cfg(cfg_path: &str) -> Result<Cfg> {
+    let content = tiny_std::fs::read_to_string(cfg_path)
+        .map_err(|e| Error::Cfg(format!("Failed to read cfg at {cfg_path}: {e}")))?;
+    let raw = RawCfg::parse(&content)?;
+    let crypttab = raw
+        .crypttab
+        .map(|path| read_tab(path, parse_crypttab))
+        .transpose()?;
+    let fstab = raw
+        .fstab
+        .map(|path| read_tab(path, parse_fstab))
+        .transpose()?;
+    raw.resolve(cfg_path, crypttab.as_deref(), fstab.as_deref())
+}
+
+fn read_tab<T>(path: &str, parse: fn(&str) -> Result<Vec<T>>) -> Result<Vec<T>> {
+    let content = tiny_std::fs::read_to_string(path).map_err(|e| {
+        Error::Cfg(format!(
+            "Failed to read tab referenced by cfg at {path}: {e}"
+        ))
+    })?;
+    parse(&content)
+}
+
+/// Checks the canonical 8-4-4-4-12 textual uuid format.
+pub fn is_uuid(s: &str) -> bool {
+    let mut groups = 0;
+    for (ind, group) in s.split('-').enumerate() {
+        let expected_len = match ind {
+            0 => 8,
+            1..=3 => 4,
+            4 => 12,
+            _ => return false,
+        };
+        if group.len() != expected_len || !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return false;
+        }
+        groups += 1;
+    }
+    groups == 5
+}
+
+struct Entry<'a> {
+    section: &'a str,
+    name: &'a str,
+    field: &'a str,
+    value: &'a str,
+    ind: usize,
+}
+
+/// The cfg file as written, before tabs are loaded and everything is merged.
+struct RawCfg<'a> {
+    root: Option<&'a str>,
+    swap: Option<&'a str>,
+    home: Option<&'a str>,
+    key_file_path: Option<&'a str>,
+    unlock: Option<&'a str>,
+    pseudo_fs: Option<&'a str>,
+    crypttab: Option<&'a str>,
+    fstab: Option<&'a str>,
+    entries: Vec<Entry<'a>>,
+}
+
+impl<'a> RawCfg<'a> {
+    fn parse(content: &'a str) -> Result<Self> {
+        let mut raw = RawCfg {
+            root: None,
+            swap: None,
+            home: None,
+            key_file_path: None,
+            unlock: None,
+            pseudo_fs: None,
+            crypttab: None,
+            fstab: None,
+            entries: Vec::new(),
+        };
+        for (ind, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            // Allow comments
+            if trimmed.starts_with("//") {
+                continue;
+            }
+            let (key, value) = trimmed.split_once('=')
+                .ok_or_else(|| Error::Cfg(format!("Found non empty line that doesn't contain '=' or starts with '//' [{ind}]: '{line}'")))?;
+            match key {
+                "root" => raw.root = Some(value),
+                "home" => raw.home = Some(value),
+                "swap" => raw.swap = Some(value),
+                "key_file_path" => raw.key_file_path = Some(value),
+                "unlock" => raw.unlock = Some(value),
+                "pseudo_fs" => raw.pseudo_fs = Some(value),
+                "crypttab" => raw.crypttab = Some(value),
+                "fstab" => raw.fstab = Some(value),
+                other => {
+                    let entry = other.split_once('.').and_then(|(section, rest)| {
+                        let (name, field) = rest.rsplit_once('.')?;
+                        Some((section, name, field))
+                    });
+                    let Some((section @ ("md" | "crypt" | "mount" | "swap" | "zram"), name, field)) =
+                        entry.filter(|(_, name, _)| !name.is_empty())
+                    else {
+                        return Err(Error::Cfg(format!(
+                            "Unrecognized key in config file {other} at [{ind}]: '{line}'"
+                        )));
+                    };
+                    raw.entries.push(Entry {
+                        section,
+                        name,
+                        field,
+                        value,
+                        ind,
+                    });
+                }
+            }
+        }
+        Ok(raw)
+    }
+
+    fn resolve(
+        self,
+        cfg_path: &str,
+        crypttab: Option<&[CrypttabEntry]>,
+        fstab: Option<&[FstabEntry]>,
+    ) -> Result<Cfg> {
+        let unlock = self
+            .unlock
+            .map(|value| parse_unlock(value, "unlock", cfg_path))
+            .transpose()?;
+        let pseudo_fs = match self.pseudo_fs {
+            None | Some("unmount") => PseudoFs::Unmount,
+            Some("move") => PseudoFs::Move,
+            Some(other) => {
+                return Err(Error::Cfg(format!(
+                    "Unrecognized pseudo_fs {other} in cfg at path {cfg_path}, expected unmount or move"
+                )))
+            }
+        };
+        let mut cfg = Cfg {
+            key_file_path: self.key_file_path.map(ToString::to_string),
+            unlock: unlock.unwrap_or(Unlock::Cryptsetup),
+            pseudo_fs,
+            md: Vec::new(),
+            crypt: Vec::new(),
+            mounts: Vec::new(),
+            swaps: Vec::new(),
+            zram: Vec::new(),
+        };
+        // Only what's backing an imported mount or swap, or marked with the `initramfs` option, is
+        // unlocked from crypttab, the rest is left to the real init. Logical volumes are imported
+        // as is, their physical volumes need the `initramfs` option or a crypt entry in the cfg.
+        let imported_crypt = crypttab.unwrap_or_default();
+        let mut swap_files = Vec::new();
+        for entry in fstab.unwrap_or_default() {
+            if entry.vfstype == "swap"
+                && entry.spec.starts_with('/')
+                && !entry.spec.starts_with("/dev/")
+            {
+                swap_files.push(entry);
+                continue;
+            }
+            let crypt = entry
+                .spec
+                .strip_prefix("/dev/mapper/")
+                .and_then(|name| imported_crypt.iter().find(|c| c.name == name));
+            let source = match crypt {
+                Some(crypt) => crypt.name.clone(),
+                None => match lv_from_device(&entry.spec) {
+                    Some(lv) => lv,
+                    None => continue,
+                },
+            };
+            if entry.vfstype == "swap" {
+                cfg.swaps.push(Swap {
+                    name: source.replace('/', "-"),
+                    source,
+                    options: entry.options.clone(),
+                    mount: None,
+                    resume_offset: None,
+                });
+            } else {
+                cfg.mounts.push(Mount {
+                    name: mount_name(&entry.file),
+                    source,
+                    target: entry.file.clone(),
+                    fs_type: (entry.vfstype != "auto").then(|| entry.vfstype.clone()),
+                    options: entry.options.clone(),
+                    subvol: None,
+                });
+            }
+        }
+        // Swap files on an imported mount, on the innermost one if they're nested
+        for entry in swap_files {
+            let Some(mount) = cfg
+                .mounts
+                .iter()
+                .filter(|m| is_under(&entry.spec, &m.target))
+                .max_by_key(|m| m.target.len())
+            else {
+                continue;
+            };
+            let swap = Swap {
+                name: mount_name(&entry.spec),
+                source: entry.spec.clone(),
+                options: entry.options.clone(),
+                mount: Some(mount.name.clone()),
+                resume_offset: None,
+            };
+            cfg.swaps.push(swap);
+        }
+        for crypt in imported_crypt {
+            // Members of a multi-device btrfs are listed as `device=` options of the mount
+            let member = format!("device=/dev/mapper/{}", crypt.name);
+            let used = crypt.options.iter().any(|opt| opt == "initramfs")
+                || cfg
+                    .mounts
+                    .iter()
+                    .any(|m| m.source == crypt.name || m.options.contains(&member))
+                || cfg.swaps.iter().any(|s| s.source == crypt.name);
+            if !used {
+                continue;
+            }
+            let uuid = crypt.uuid().ok_or_else(|| {
+                Error::Cfg(format!(
+                    "crypttab entry {} needs to reference its device as UUID=<uuid>, found {}",
+                    crypt.name, crypt.device
+                ))
+            })?;
+            cfg.crypt.push(CryptVolume {
+                name: crypt.name.clone(),
+                uuid: uuid.to_string(),
+                key_file: crypt.key_file.clone(),
+                key_mount: None,
+                options: crypt.options.clone(),
+                unlock: None,
+                header_sha256: None,
+            });
+        }
+
+        if let Some(uuid) = self.root {
+            crypt_entry(&mut cfg.crypt, "croot").uuid = uuid.to_string();
+            let mount = mount_entry(&mut cfg.mounts, "root");
+            mount.source = "croot".to_string();
+            mount.target = "/".to_string();
+        }
+        if let Some(uuid) = self.swap {
+            crypt_entry(&mut cfg.crypt, "cswap").uuid = uuid.to_string();
+            swap_entry(&mut cfg.swaps, "cswap").source = "cswap".to_string();
+        }
+        if let Some(uuid) = self.home {
+            crypt_entry(&mut cfg.crypt, "chome").uuid = uuid.to_string();
+            let mount = mount_entry(&mut cfg.mounts, "home");
+            mount.source = "chome".to_string();
+            mount.target = "/home".to_string();
+        }
+
+        for entry in &self.entries {
+            let value = entry.value.to_string();
+            let known = match entry.section {
+                "md" => {
+                    let md = md_entry(&mut cfg.md, entry.name);
+                    match entry.field {
+                        "uuid" => md.uuid = value,
+                        "degraded" => {
+                            md.degraded = match entry.value {
+                                "fail" => DegradedPolicy::Fail,
+                                "start" => DegradedPolicy::Start,
+                                other => return Err(Error::Cfg(format!(
+                                    "Unrecognized degraded policy {other} for md.{} at [{}] in cfg at path {cfg_path}, expected fail or start",
+                                    entry.name, entry.ind
+                                ))),
+                            }
+                        }
+                        "timeout" => {
+                            md.timeout_secs = entry.value.parse().map_err(|_| {
+                                Error::Cfg(format!(
+                                    "Malformed timeout {} for md.{} at [{}] in cfg at path {cfg_path}, expected seconds",
+                                    entry.value, entry.name, entry.ind
+                                ))
+                            })?;
+                        }
+                        _ => {}
+                    }
+                    matches!(entry.field, "uuid" | "degraded" | "timeout")
+                }
+                "crypt" => {
+                    let crypt = crypt_entry(&mut cfg.crypt, entry.name);
+                    match entry.field {
+                        "uuid" => crypt.uuid = value,
+                        "key_file" => crypt.key_file = Some(value),
+                        "key_mount" => crypt.key_mount = Some(value),
+                        "options" => crypt.options = split_options(entry.value),
+                        "unlock" => {
+                            let key = format!("crypt.{}.unlock at [{}]", entry.name, entry.ind);
+                            crypt.unlock = Some(parse_unlock(entry.value, &key, cfg_path)?);
+                        }
+                        "header_sha256" => crypt.header_sha256 = Some(value),
+                        _ => {}
+                    }
+                    matches!(
+                        entry.field,
+                        "uuid" | "key_file" | "key_mount" | "options" | "unlock" | "header_sha256"
+                    )
+                }
+                "mount" => {
+                    let mount = mount_entry(&mut cfg.mounts, entry.name);
+                    match entry.field {
+                        "source" => mount.source = value,
+                        "target" => mount.target = value,
+                        "fs" => mount.fs_type = (value != "auto").then_some(value),
+                        "options" => mount.options = split_options(entry.value),
+                        "subvol" => mount.subvol = Some(value),
+                        _ => {}
+                    }
+                    matches!(
+                        entry.field,
+                        "source" | "target" | "fs" | "options" | "subvol"
+                    )
+                }
+                "zram" => {
+                    let zram = zram_entry(&mut cfg.zram, entry.name);
+                    match entry.field {
+                        "size" => {
+                            zram.size = parse_zram_size(entry.value).ok_or_else(|| {
+                                Error::Cfg(format!(
+                                    "Malformed size {} for zram.{} at [{}] in cfg at path {cfg_path}, expected bytes with an optional K, M or G suffix, or a percentage of memory",
+                                    entry.value, entry.name, entry.ind
+                                ))
+                            })?;
+                        }
+                        "algorithm" => zram.algorithm = Some(value),
+                        "priority" => {
+                            zram.priority = Some(
+                                entry
+                                    .value
+                                    .parse()
+                                    .ok()
+                                    .filter(|prio| *prio <= 32767)
+                                    .ok_or_else(|| {
+                                        Error::Cfg(format!(
+                                            "Malformed priority {} for zram.{} at [{}] in cfg at path {cfg_path}, expected 0 to 32767",
+                                            entry.value, entry.name, entry.ind
+                                        ))
+                                    })?,
+                            );
+                        }
+                        _ => {}
+                    }
+                    matches!(entry.field, "size" | "algorithm" | "priority")
+                }
+                _ => {
+                    let swap = swap_entry(&mut cfg.swaps, entry.name);
+                    match entry.field {
+                        "source" => swap.source = value,
+                        "options" => swap.options = split_options(entry.value),
+                        "mount" => swap.mount = Some(value),
+                        "resume_offset" => {
+                            swap.resume_offset = Some(entry.value.parse().map_err(|_| {
+                                Error::Cfg(format!(
+                                    "Malformed resume_offset {} for swap.{} at [{}] in cfg at path {cfg_path}, expected a page number",
+                                    entry.value, entry.name, entry.ind
+                                ))
+                            })?);
+                        }
+                        _ => {}
+                    }
+                    matches!(
+                        entry.field,
+                        "source" | "options" | "mount" | "resume_offset"
+                    )
+                }
+            };
+            if !known {
+                return Err(Error::Cfg(format!(
+                    "Unrecognized field {} for {}.{} at [{}] in cfg at path {cfg_path}",
+                    entry.field, entry.section, entry.name, entry.ind
+                )));
+            }
+        }
+        validate(&cfg, cfg_path)?;
+        Ok(cfg)
+    }
+}
+
+fn validate(cfg: &Cfg, cfg_path: &str) -> Result<()> {
+    for md in &cfg.md {
+        if !is_md_uuid(&md.uuid) {
+            return Err(Error::Cfg(format!(
+                "Malformed md.{} uuid in cfg at path {cfg_path}: '{}', expected 32 hex digits",
+                md.name, md.uuid
+            )));
+        }
+    }
+    for crypt in &cfg.crypt {
+        if !is_uuid(&crypt.uuid) {
+            return Err(Error::Cfg(format!(
+                "Malformed {} uuid in cfg at path {cfg_path}: '{}', expected 8-4-4-4-12 hex digits",
+                crypt.name, crypt.uuid
+            )));
+        }
+        let options = parse_crypt_options(&crypt.options).map_err(|e| {
+            Error::Cfg(format!(
+                "Bad options for crypt volume {} in cfg at path {cfg_path}: {e:?}",
+                crypt.name
+            ))
+        })?;
+        if let Some(sha256) = &crypt.header_sha256 {
+            if options.header.is_none() {
+                return Err(Error::Cfg(format!(
+                    "crypt.{}.header_sha256 is set but there's no header= option for it in cfg at path {cfg_path}",
+                    crypt.name
+                )));
+            }
+            if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(Error::Cfg(format!(
+                    "Malformed crypt.{}.header_sha256 in cfg at path {cfg_path}: '{sha256}', expected 64 hex digits",
+                    crypt.name
+                )));
+            }
+        }
+        if cfg.key_file_for(crypt).is_none() {
+            return Err(Error::Cfg(format!(
+                "No key_file for {} and no key_file_path found in cfg at path {cfg_path}",
+                crypt.name
+            )));
+        }
+    }
+    let mut has_root = false;
+    for mount in &cfg.mounts {
+        if !mount.target.starts_with('/') {
+            return Err(Error::Cfg(format!(
+                "Mount {} needs an absolute target, found '{}' in cfg at path {cfg_path}",
+                mount.name, mount.target
+            )));
+        }
+        has_root |= mount.target == "/";
+        parse_mount_options(&mount.options).map_err(|e| {
+            Error::Cfg(format!(
+                "Bad options for mount {} in cfg at path {cfg_path}: {e:?}",
+                mount.name
+            ))
+        })?;
+        check_source(cfg, "Mount", &mount.name, &mount.source, cfg_path)?;
+    }
+    if !has_root {
+        return Err(Error::Cfg(format!(
+            "No root mount found in cfg at path {cfg_path}"
+        )));
+    }
+    for swap in &cfg.swaps {
+        check_source(cfg, "Swap", &swap.name, &swap.source, cfg_path)?;
+        parse_swap_options(&swap.options).map_err(|e| {
+            Error::Cfg(format!(
+                "Bad options for swap {} in cfg at path {cfg_path}: {e:?}",
+                swap.name
+            ))
+        })?;
+    }
+    for zram in &cfg.zram {
+        if matches!(zram.size, ZramSize::Bytes(0) | ZramSize::Percent(0)) {
+            return Err(Error::Cfg(format!(
+                "zram.{} needs a size in cfg at path {cfg_path}",
+                zram.name
+            )));
+        }
+    }
+    for crypt in &cfg.crypt {
+        check_key_mount(cfg, crypt, cfg_path)?;
+    }
+    for swap in &cfg.swaps {
+        check_swap_file(cfg, swap, cfg_path)?;
+    }
+    plan(cfg).map_err(|e| {
+        Error::Cfg(format!(
+            "Can't order volumes and mounts in cfg at path {cfg_path}: {e:?}"
+        ))
+    })?;
+    Ok(())
+}
+
+/// A key on a mount needs its own key file on that mount.
+fn check_key_mount(cfg: &Cfg, crypt: &CryptVolume, cfg_path: &str) -> Result<()> {
+    let Some(key_mount) = &crypt.key_mount else {
+        return Ok(());
+    };
+    let mount = cfg
+        .mounts
+        .iter()
+        .find(|m| &m.name == key_mount)
+        .ok_or_else(|| {
+            Error::Cfg(format!(
+                "crypt.{}.key_mount references unknown mount {key_mount} in cfg at path {cfg_path}",
+                crypt.name
+            ))
+        })?;
+    let Some(key_file) = crypt.key_file.as_deref() else {
+        return Err(Error::Cfg(format!(
+            "crypt.{}.key_mount is set but there's no crypt.{}.key_file in cfg at path {cfg_path}",
+            crypt.name, crypt.name
+        )));
+    };
+    if !key_file.starts_with('/') || !is_under(key_file, &mount.target) {
+        return Err(Error::Cfg(format!(
+            "crypt.{}.key_file needs to be a path on mount {key_mount} at {}, found '{key_file}' in cfg at path {cfg_path}",
+            crypt.name, mount.target
+        )));
+    }
+    Ok(())
+}
+
+/// A swap file needs an existing mount and its path on that mount, only swap files have a
+/// resume offset.
+fn check_swap_file(cfg: &Cfg, swap: &Swap, cfg_path: &str) -> Result<()> {
+    let Some(mount_name) = &swap.mount else {
+        if swap.resume_offset.is_some() {
+            return Err(Error::Cfg(format!(
+                "swap.{}.resume_offset is set but swap.{}.mount isn't, only swap files have an offset in cfg at path {cfg_path}",
+                swap.name, swap.name
+            )));
+        }
+        return Ok(());
+    };
+    let mount = cfg
+        .mounts
+        .iter()
+        .find(|m| &m.name == mount_name)
+        .ok_or_else(|| {
+            Error::Cfg(format!(
+                "swap.{}.mount references unknown mount {mount_name} in cfg at path {cfg_path}",
+                swap.name
+            ))
+        })?;
+    if !swap.source.starts_with('/') || !is_under(&swap.source, &mount.target) {
+        return Err(Error::Cfg(format!(
+            "swap.{}.source needs to be a path on mount {mount_name} at {}, found '{}' in cfg at path {cfg_path}",
+            swap.name, mount.target, swap.source
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `path` is `dir` or somewhere below it.
+pub(crate) fn is_under(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn check_source(cfg: &Cfg, kind: &str, name: &str, source: &str, cfg_path: &str) -> Result<()> {
+    if source.is_empty() {
+        return Err(Error::Cfg(format!(
+            "{kind} {name} has no source in cfg at path {cfg_path}"
+        )));
+    }
+    if !source.starts_with('/') && cfg.crypt_volume(source).is_none() && parse_lv(source).is_none()
+    {
+        return Err(Error::Cfg(format!(
+            "{kind} {name} has source {source} which is neither a crypt volume, a vg/lv nor a device path in cfg at path {cfg_path}"
+        )));
+    }
+    Ok(())
+}
+
+fn parse_unlock(value: &str, key: &str, cfg_path: &str) -> Result<Unlock> {
+    match value {
+        "cryptsetup" => Ok(Unlock::Cryptsetup),
+        "native" => Ok(Unlock::Native),
+        other => Err(Error::Cfg(format!(
+            "Unrecognized unlock method {other} for {key} in cfg at path {cfg_path}, expected cryptsetup or native"
+        ))),
+    }
+}
+
+/// `4G`, `512M`, `1048576` or `50%`
+fn parse_zram_size(size: &str) -> Option<ZramSize> {
+    if let Some(percent) = size.strip_suffix('%') {
+        return percent.parse().ok().map(ZramSize::Percent);
+    }
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .map(ZramSize::Bytes)
+}
+
+/// `/` -> `root`, `/var/lib` -> `var-lib`
+fn mount_name(target: &str) -> String {
+    let trimmed = target.trim_matches('/');
+    if trimmed.is_empty() {
+        "root".to_string()
+    } else {
+        trimmed.replace('/', "-")
+    }
+}
+
+fn split_options(options: &str) -> Vec<String> {
+    options
+        .split(',')
+        .filter(|opt| !opt.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn md_entry<'a>(md: &'a mut Vec<MdArray>, name: &str) -> &'a mut MdArray {
+    if let Some(ind) = md.iter().position(|m| m.name == name) {
+        return &mut md[ind];
+    }
+    md.push(MdArray {
+        name: name.to_string(),
+        uuid: String::new(),
+        degraded: DegradedPolicy::Fail,
+        timeout_secs: 10,
+    });
+    md.last_mut().unwrap()
+}
+
+fn crypt_entry<'a>(crypt: &'a mut Vec<CryptVolume>, name: &str) -> &'a mut CryptVolume {
+    if let Some(ind) = crypt.iter().position(|c| c.name == name) {
+        return &mut crypt[ind];
+    }
+    crypt.push(CryptVolume {
+        name: name.to_string(),
+        uuid: String::new(),
+        key_file: None,
+        key_mount: None,
+        options: Vec::new(),
+        unlock: None,
+        header_sha256: None,
+    });
+    crypt.last_mut().unwrap()
+}
+
+fn mount_entry<'a>(mounts: &'a mut Vec<Mount>, name: &str) -> &'a mut Mount {
+    if let Some(ind) = mounts.iter().position(|m| m.name == name) {
+        return &mut mounts[ind];
+    }
+    mounts.push(Mount {
+        name: name.to_string(),
+        source: String::new(),
+        target: String::new(),
+        fs_type: None,
+        options: Vec::new(),
+        subvol: None,
+    });
+    mounts.last_mut().unwrap()
+}
+
+fn swap_entry<'a>(swaps: &'a mut Vec<Swap>, name: &str) -> &'a mut Swap {
+    if let Some(ind) = swaps.iter().position(|s| s.name == name) {
+        return &mut swaps[ind];
+    }
+    swaps.push(Swap {
+        name: name.to_string(),
+        source: String::new(),
+        options: Vec::new(),
+        mount: None,
+        resume_offset: None,
+    });
+    swaps.last_mut().unwrap()
+}
+
+fn zram_entry<'a>(zram: &'a mut Vec<Zram>, name: &str) -> &'a mut Zram {
+    if let Some(ind) = zram.iter().position(|z| z.name == name) {
+        return &mut zram[ind];
+    }
+    zram.push(Zram {
+        name: name.to_string(),
+        size: ZramSize::Bytes(0),
+        algorithm: None,
+        priority: None,
+    });
+    zram.last_mut().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const ROOT: &str = "7e127144-f930-4656-84b4-d6a27fa7caa6";
+    const SWAP: &str = "c1c1f5cf-f248-4269-82d3-99d1a6004e1e";
+    const HOME: &str = "d2059da0-7eae-4396-badf-e6ed0ccfd04b";
+
+    fn resolve(content: &str, crypttab: &str, fstab: &str) -> Result<Cfg> {
+        let crypttab = parse_crypttab(crypttab).unwrap();
+        let fstab = parse_fstab(fstab).unwrap();
+        RawCfg::parse(content)?.resolve("test.cfg", Some(&crypttab), Some(&fstab))
+    }
+
+    #[test]
+    fn validates_uuids() {
+        assert!(is_uuid(ROOT));
+        assert!(is_uuid("7E127144-F930-4656-84B4-D6A27FA7CAA6"));
+        assert!(!is_uuid("7e127144-f930-4656-84b4-d6a27fa7caa"));
+        assert!(!is_uuid("7e127144f930465684b4d6a27fa7caa6"));
+        assert!(!is_uuid("7e127144-f930-4656-84b4-d6a27fa7caa6-00"));
+        assert!(!is_uuid("7e127144-f930-4656-84b4-d6a27fa7cxa6"));
+        assert!(!is_uuid(""));
+    }
+
+    #[test]
+    fn shorthands() {
+        let cfg = resolve(
+            &format!("root={ROOT}\nswap={SWAP}\n// comment\nhome={HOME}\nkey_file_path=/key\n"),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(3, cfg.crypt.len());
+        assert_eq!(ROOT, cfg.crypt_volume("croot").unwrap().uuid);
+        assert_eq!(
+            vec!["/", "/home"],
+            cfg.mounts_in_order()
+                .iter()
+                .map(|m| m.target.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("cswap", cfg.swaps[0].source);
+        assert!(resolve(&format!("root={ROOT}\n"), "", "").is_err());
+        assert!(resolve("root=not-a-uuid\nkey_file_path=/key\n", "", "").is_err());
+    }
+
+    #[test]
+    fn imports_tabs_with_overrides() {
+        let crypttab = format!(
+            "root_crypt UUID={ROOT} none luks,discard\n\
+            home_crypt UUID={HOME} /etc/keys/home.key\n\
+            swap_crypt UUID={SWAP} none\n\
+            data_crypt UUID={SWAP} none\n"
+        );
+        let fstab = "/dev/mapper/root_crypt / ext4 noatime 0 1\n\
+            /dev/mapper/home_crypt /home auto defaults 0 2\n\
+            /dev/mapper/swap_crypt none swap sw 0 0\n\
+            UUID=1234-ABCD /boot vfat defaults 0 2\n";
+        let cfg = resolve(
+            "key_file_path=/key\nfstab=/fstab\ncrypttab=/crypttab\n\
+            crypt.home_crypt.key_file=/home.key\nmount.home.options=nodev,nosuid\n\
+            unlock=native\ncrypt.root_crypt.unlock=cryptsetup\n",
+            &crypttab,
+            fstab,
+        )
+        .unwrap();
+        // data_crypt isn't mounted by anything
+        assert_eq!(3, cfg.crypt.len());
+        let home = cfg.crypt_volume("home_crypt").unwrap();
+        assert_eq!(Some("/home.key"), cfg.key_file_for(home));
+        let root = cfg.crypt_volume("root_crypt").unwrap();
+        assert_eq!(Some("/key"), cfg.key_file_for(root));
+        assert_eq!(Unlock::Cryptsetup, cfg.unlock_for(root));
+        assert_eq!(Unlock::Native, cfg.unlock_for(home));
+        assert_eq!(
+            vec!["luks".to_string(), "discard".to_string()],
+            root.options
+        );
+        assert_eq!(2, cfg.mounts.len());
+        let home_mount = cfg.mounts.iter().find(|m| m.name == "home").unwrap();
+        assert_eq!(None, home_mount.fs_type);
+        assert_eq!(
+            vec!["nodev".to_string(), "nosuid".to_string()],
+            home_mount.options
+        );
+        assert_eq!("swap_crypt", cfg.swaps[0].source);
+    }
+
+    #[test]
+    fn btrfs_subvolumes_and_members() {
+        let crypttab = format!(
+            "root_crypt UUID={ROOT} none luks\n\
+            second_crypt UUID={HOME} none luks\n"
+        );
+        let fstab = "/dev/mapper/root_crypt / btrfs subvol=@,device=/dev/mapper/second_crypt 0 0\n";
+        let cfg = resolve(
+            "key_file_path=/key\nmount.home.source=root_crypt\nmount.home.target=/home\n\
+            mount.home.subvol=@home\n",
+            &crypttab,
+            fstab,
+        )
+        .unwrap();
+        // The second device is unlocked even though nothing mounts it directly
+        assert_eq!(2, cfg.crypt.len());
+        let home = cfg.mounts.iter().find(|m| m.name == "home").unwrap();
+        assert_eq!(Some("@home"), home.subvol.as_deref());
+        assert_eq!("root_crypt", home.source);
+    }
+
+    #[test]
+    fn logical_volumes() {
+        let crypttab = format!("pv_crypt UUID={ROOT} none luks,initramfs\n");
+        let fstab = "/dev/mapper/vg0-root / ext4 defaults 0 1\n\
+            /dev/vg0/swap none swap sw 0 0\n";
+        let cfg = resolve(
+            "key_file_path=/key\nmount.home.source=vg1/home\nmount.home.target=/home\n",
+            &crypttab,
+            fstab,
+        )
+        .unwrap();
+        assert_eq!(1, cfg.crypt.len());
+        assert_eq!(vec!["vg0", "vg1"], cfg.volume_groups());
+        assert_eq!("/dev/mapper/vg0-swap", source_device(&cfg.swaps[0].source));
+    }
+
+    #[test]
+    fn chained_keys() {
+        let crypttab = format!(
+            "root_crypt UUID={ROOT} none luks\n\
+            home_crypt UUID={HOME} /etc/keys/home.key luks\n"
+        );
+        let fstab = "/dev/mapper/root_crypt / ext4 defaults 0 1\n\
+            /dev/mapper/home_crypt /home ext4 defaults 0 2\n";
+        let base = "key_file_path=/key\ncrypt.home_crypt.key_mount=root\n";
+        let cfg = resolve(base, &crypttab, fstab).unwrap();
+        let home = cfg.crypt_volume("home_crypt").unwrap();
+        assert_eq!(
+            Some("/mnt/root/etc/keys/home.key".to_string()),
+            cfg.key_path_for(home)
+        );
+        let root = cfg.crypt_volume("root_crypt").unwrap();
+        assert_eq!(Some("/key".to_string()), cfg.key_path_for(root));
+        assert!(resolve(
+            "key_file_path=/key\ncrypt.home_crypt.key_mount=usr\n",
+            &crypttab,
+            fstab
+        )
+        .is_err());
+        // The key has to be on the mount it names
+        assert!(resolve(
+            &format!("{base}crypt.home_crypt.key_file=/home.key\nmount.boot.source=/dev/sda1\nmount.boot.target=/boot\ncrypt.home_crypt.key_mount=boot\n"),
+            &crypttab,
+            fstab
+        )
+        .is_err());
+        // Home's key on home
+        assert!(resolve(
+            "key_file_path=/key\ncrypt.home_crypt.key_file=/home/home.key\ncrypt.home_crypt.key_mount=home\n",
+            &crypttab,
+            fstab
+        )
+        .is_err());
+        assert!(is_under("/home/a", "/home"));
+        assert!(is_under("/home", "/home/"));
+        assert!(!is_under("/homes/a", "/home"));
+    }
+
+    #[test]
+    fn zram_devices() {
+        let base = format!("root={ROOT}\nkey_file_path=/key\n");
+        let cfg = resolve(
+            &format!("{base}zram.zram0.size=50%\nzram.zram0.algorithm=zstd\nzram.zram0.priority=100\nzram.big.size=4G\n"),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            Zram {
+                name: "zram0".to_string(),
+                size: ZramSize::Percent(50),
+                algorithm: Some("zstd".to_string()),
+                priority: Some(100),
+            },
+            cfg.zram[0]
+        );
+        assert_eq!(ZramSize::Bytes(4 << 30), cfg.zram[1].size);
+        // The kernel knows which algorithms it has, only the size is required
+        let cfg = resolve(
+            &format!("{base}zram.zram0.size=1G\nzram.zram0.algorithm=lz4\n"),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(Some("lz4".to_string()), cfg.zram[0].algorithm);
+        let err = resolve(&format!("{base}zram.zram0.algorithm=lz4\n"), "", "").unwrap_err();
+        assert!(matches!(err, Error::Cfg(ref msg) if msg.contains("needs a size")));
+        assert!(resolve(&format!("{base}zram.zram0.size=4T\n"), "", "").is_err());
+        assert!(resolve(
+            &format!("{base}zram.zram0.size=1G\nzram.zram0.priority=-1\n"),
+            "",
+            ""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn swap_files() {
+        let crypttab = format!("root_crypt UUID={ROOT} none luks\n");
+        let fstab = "/dev/mapper/root_crypt / ext4 defaults 0 1\n\
+            /swapfile none swap sw,pri=5 0 0\n\
+            /data/swapfile none swap sw 0 0\n";
+        let cfg = resolve(
+            "key_file_path=/key\nswap.swapfile.resume_offset=34816\n",
+            &crypttab,
+            fstab,
+        )
+        .unwrap();
+        assert_eq!(
+            Swap {
+                name: "swapfile".to_string(),
+                source: "/swapfile".to_string(),
+                options: vec!["sw".to_string(), "pri=5".to_string()],
+                mount: Some("root".to_string()),
+                resume_offset: Some(34816),
+            },
+            cfg.swaps[0]
+        );
+        // Both on root, /data isn't a mount
+        assert_eq!(Some("root"), cfg.swaps[1].mount.as_deref());
+        let base = format!("root={ROOT}\nkey_file_path=/key\nswap.swapfile.source=/swapfile\n");
+        assert!(resolve(&format!("{base}swap.swapfile.mount=root\n"), "", "").is_ok());
+        assert!(resolve(&format!("{base}swap.swapfile.mount=home\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}swap.swapfile.resume_offset=1\n"), "", "").is_err());
+        assert!(resolve(
+            &format!("{base}swap.swapfile.mount=root\nswap.swapfile.resume_offset=-1\n"),
+            "",
+            ""
+        )
+        .is_err());
+        let home = format!(
+            "{base}swap.swapfile.mount=home\nmount.home.source=/dev/sda2\nmount.home.target=/home\n"
+        );
+        // Not under /home
+        assert!(resolve(&home, "", "").is_err());
+    }
+
+    #[test]
+    fn md_arrays() {
+        let base = format!("root={ROOT}\nkey_file_path=/key\n");
+        let cfg = resolve(
+            &format!("{base}md.mirror.uuid=3b1a4e6c:8d2f5a90:1c7e4b3d:9f0a2e6b\nmd.mirror.degraded=start\n"),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(DegradedPolicy::Start, cfg.md[0].degraded);
+        assert_eq!(10, cfg.md[0].timeout_secs);
+        assert!(resolve(&format!("{base}md.mirror.uuid=3b1a4e6c\n"), "", "").is_err());
+        assert!(resolve(
+            &format!("{base}md.mirror.uuid=3b1a4e6c:8d2f5a90:1c7e4b3d:9f0a2e6b\nmd.mirror.timeout=soon\n"),
+            "",
+            ""
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        let base = format!("root={ROOT}\nkey_file_path=/key\n");
+        assert!(resolve(&format!("{base}mount.home.bogus=1\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}mount.root.options=noatim\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}crypt.croot.options=key-slot=x\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}unlock=systemd\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}pseudo_fs=keep\n"), "", "").is_err());
+        let cfg = resolve(&format!("{base}pseudo_fs=move\n"), "", "").unwrap();
+        assert_eq!(PseudoFs::Move, cfg.pseudo_fs);
+        let swap = format!("{base}swap={SWAP}\n");
+        assert!(resolve(
+            &format!("{swap}swap.cswap.options=pri=10,discard\n"),
+            "",
+            ""
+        )
+        .is_ok());
+        assert!(resolve(&format!("{swap}swap.cswap.options=pri=high\n"), "", "").is_err());
+        let sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert!(resolve(
+            &format!("{base}crypt.croot.header_sha256={sha256}\n"),
+            "",
+            ""
+        )
+        .is_err());
+        assert!(resolve(
+            &format!(
+                "{base}crypt.croot.options=header=/root.hdr\ncrypt.croot.header_sha256=9f86\n"
+            ),
+            "",
+            ""
+        )
+        .is_err());
+        let cfg = resolve(
+            &format!(
+                "{base}crypt.croot.options=header=/root.hdr\ncrypt.croot.header_sha256={sha256}\n"
+            ),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(Some(sha256), cfg.crypt[0].header_sha256.as_deref());
+        assert!(resolve(&format!("{base}volume.home.uuid={HOME}\n"), "", "").is_err());
+        assert!(resolve(&format!("{base}mount.home.target=/home\n"), "", "").is_err());
+        assert!(resolve(
+            &format!("{base}mount.home.target=/home\nmount.home.source=missing\n"),
+            "",
+            ""
+        )
+        .is_err());
+        let cfg = resolve(
+            &format!("{base}mount.var.target=/var/lib\nmount.var.source=/dev/sda3\n"),
+            "",
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["/", "/var/lib"],
+            cfg.mounts_in_order()
+                .iter()
+                .map(|m| m.target.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! crypttab style options of a [`crate::cfg::CryptVolume`], see crypttab(5), translated into
+//! `cryptsetup open` arguments. Both the crypttab and the cryptsetup spelling are accepted,
+//! `discard` and `allow-discards`, `no-read-workqueue` and `perf-no_read_workqueue` and so on.
+use crate::error::{Error, Result};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CryptType {
+    #[default]
+    Luks,
+    Plain,
+    Tcrypt,
+    Bitlk,
+}
+
+impl CryptType {
+    fn name(self) -> &'static str {
+        match self {
+            CryptType::Luks => "luks",
+            CryptType::Plain => "plain",
+            CryptType::Tcrypt => "tcrypt",
+            CryptType::Bitlk => "bitlk",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CryptOptions {
+    pub kind: CryptType,
+    pub read_only: bool,
+    pub allow_discards: bool,
+    /// dm-crypt performance flags by their dm-crypt names, `same_cpu_crypt` etc.
+    pub perf: Vec<&'static str>,
+    /// Detached header path in the image
+    pub header: Option<String>,
+    /// Only try this keyslot
+    pub key_slot: Option<u32>,
+    /// Bytes skipped at the start of the key file
+    pub keyfile_offset: Option<u64>,
+    /// Bytes read from the key file, all of it after the offset if not set
+    pub keyfile_size: Option<u64>,
+    /// Plain mode parameters
+    pub cipher: Option<String>,
+    pub hash: Option<String>,
+    /// Key size in bits
+    pub key_size: Option<u32>,
+    /// Start of the data in 512 byte sectors
+    pub offset: Option<u64>,
+    /// IV offset in 512 byte sectors
+    pub skip: Option<u64>,
+}
+
+/// crypttab and cryptsetup spellings of the dm-crypt performance flags.
+const PERF_OPTIONS: &[(&str, &str, &str)] = &[
+    ("same-cpu-crypt", "perf-same_cpu_crypt", "same_cpu_crypt"),
+    (
+        "submit-from-crypt-cpus",
+        "perf-submit_from_crypt_cpus",
+        "submit_from_crypt_cpus",
+    ),
+    (
+        "no-read-workqueue",
+        "perf-no_read_workqueue",
+        "no_read_workqueue",
+    ),
+    (
+        "no-write-workqueue",
+        "perf-no_write_workqueue",
+        "no_write_workqueue",
+    ),
+];
+
+/// Only meaningful to systemd-cryptsetup or the init system, nothing to do for them here.
+const USERSPACE_OPTIONS: &[&str] = &[
+    "nofail",
+    "noauto",
+    "auto",
+    "initramfs",
+    "noearly",
+    "_netdev",
+    "swap",
+    "tmp",
+    "verify",
+];
+
+/// Userspace only options with a value.
+const USERSPACE_KEYS: &[&str] = &[
+    "tries",
+    "timeout",
+    "keyfile-timeout",
+    "password-echo",
+    "headless",
+    "tmp",
+];
+
+impl CryptOptions {
+    /// Arguments for `cryptsetup open` before the device and name.
+    pub fn cryptsetup_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        args.push(format!("--type={}", self.kind.name()));
+        if self.read_only {
+            args.push("--readonly".to_string());
+        }
+        if self.allow_discards {
+            args.push("--allow-discards".to_string());
+        }
+        for flag in &self.perf {
+            args.push(format!("--perf-{flag}"));
+        }
+        if let Some(header) = &self.header {
+            args.push(format!("--header={header}"));
+        }
+        if let Some(slot) = self.key_slot {
+            args.push(format!("--key-slot={slot}"));
+        }
+        if let Some(offset) = self.keyfile_offset {
+            args.push(format!("--keyfile-offset={offset}"));
+        }
+        if let Some(size) = self.keyfile_size {
+            args.push(format!("--keyfile-size={size}"));
+        }
+        if let Some(cipher) = &self.cipher {
+            args.push(format!("--cipher={cipher}"));
+        }
+        if let Some(hash) = &self.hash {
+            args.push(format!("--hash={hash}"));
+        }
+        if let Some(size) = self.key_size {
+            args.push(format!("--key-size={size}"));
+        }
+        if let Some(offset) = self.offset {
+            args.push(format!("--offset={offset}"));
+        }
+        if let Some(skip) = self.skip {
+            args.push(format!("--skip={skip}"));
+        }
+        args
+    }
+
+    /// The part of `key` that's used, according to `keyfile-offset` and `keyfile-size`.
+    pub fn key_slice<'a>(&self, key: &'a [u8]) -> Result<&'a [u8]> {
+        let offset = self.keyfile_offset.unwrap_or(0) as usize;
+        let rest = key.get(offset..).ok_or_else(|| {
+            Error::Crypt(format!(
+                "Key file is {} bytes, shorter than keyfile-offset {offset}",
+                key.len()
+            ))
+        })?;
+        match self.keyfile_size {
+            Some(size) => rest.get(..size as usize).ok_or_else(|| {
+                Error::Crypt(format!(
+                    "Key file has {} bytes after keyfile-offset, fewer than keyfile-size {size}",
+                    rest.len()
+                ))
+            }),
+            None => Ok(rest),
+        }
+    }
+}
+
+pub fn parse_crypt_options<S: AsRef<str>>(options: &[S]) -> Result<CryptOptions> {
+    let mut parsed = CryptOptions::default();
+    let mut kind = None;
+    let mut set_kind = |new: CryptType| match kind {
+        Some(old) if old != new => Err(Error::Cfg(format!(
+            "Conflicting crypt types {} and {}",
+            CryptType::name(old),
+            new.name()
+        ))),
+        _ => {
+            kind = Some(new);
+            Ok(())
+        }
+    };
+    for opt in options {
+        let opt = opt.as_ref();
+        if let Some((_, _, flag)) = PERF_OPTIONS
+            .iter()
+            .find(|(crypttab, cryptsetup, _)| *crypttab == opt || *cryptsetup == opt)
+        {
+            if !parsed.perf.contains(flag) {
+                parsed.perf.push(flag);
+            }
+            continue;
+        }
+        match opt {
+            "discard" | "allow-discards" => parsed.allow_discards = true,
+            "readonly" | "read-only" => parsed.read_only = true,
+            "luks" => set_kind(CryptType::Luks)?,
+            "plain" => set_kind(CryptType::Plain)?,
+            "tcrypt" => set_kind(CryptType::Tcrypt)?,
+            "bitlk" => set_kind(CryptType::Bitlk)?,
+            _ if USERSPACE_OPTIONS.contains(&opt) || opt.starts_with("x-") => {}
+            _ => {
+                let Some((key, value)) = opt.split_once('=') else {
+                    return Err(Error::Cfg(format!("Unknown crypt option '{opt}'")));
+                };
+                if value.is_empty() {
+                    return Err(Error::Cfg(format!("Crypt option '{opt}' needs a value")));
+                }
+                match key {
+                    "type" => set_kind(match value {
+                        "luks" | "luks1" | "luks2" => CryptType::Luks,
+                        "plain" => CryptType::Plain,
+                        "tcrypt" => CryptType::Tcrypt,
+                        "bitlk" => CryptType::Bitlk,
+                        other => {
+                            return Err(Error::Cfg(format!(
+                            "Unknown crypt type '{other}', expected plain, luks, tcrypt or bitlk"
+                        )))
+                        }
+                    })?,
+                    "header" => {
+                        if !value.starts_with('/') {
+                            return Err(Error::Cfg(format!(
+                                "Crypt header needs an absolute path in the image, found '{value}'"
+                            )));
+                        }
+                        parsed.header = Some(value.to_string());
+                    }
+                    "key-slot" | "keyslot" => parsed.key_slot = Some(number(key, value)?),
+                    "keyfile-offset" => parsed.keyfile_offset = Some(number(key, value)?),
+                    "keyfile-size" => parsed.keyfile_size = Some(number(key, value)?),
+                    "cipher" => parsed.cipher = Some(value.to_string()),
+                    "hash" => parsed.hash = Some(value.to_string()),
+                    "size" => parsed.key_size = Some(number(key, value)?),
+                    "offset" => parsed.offset = Some(number(key, value)?),
+                    "skip" => parsed.skip = Some(number(key, value)?),
+                    _ if USERSPACE_KEYS.contains(&key) => {}
+                    _ => return Err(Error::Cfg(format!("Unknown crypt option '{opt}'"))),
+                }
+            }
+        }
+    }
+    parsed.kind = kind.unwrap_or_default();
+    if parsed.kind != CryptType::Plain {
+        if let Some(opt) = [
+            ("cipher", parsed.cipher.is_some()),
+            ("hash", parsed.hash.is_some()),
+            ("size", parsed.key_size.is_some()),
+        ]
+        .iter()
+        .find_map(|(opt, set)| set.then_some(opt))
+        {
+            return Err(Error::Cfg(format!(
+                "Crypt option {opt} only applies to plain mode, the {} header specifies it",
+                parsed.kind.name()
+            )));
+        }
+    }
+    if parsed.kind == CryptType::Plain && parsed.key_slot.is_some() {
+        return Err(Error::Cfg(
+            "Crypt option key-slot doesn't apply to plain mode".to_string(),
+        ));
+    }
+    if parsed.keyfile_size == Some(0) {
+        return Err(Error::Cfg(
+            "Crypt option keyfile-size can't be 0".to_string(),
+        ));
+    }
+    Ok(parsed)
+}
+
+fn number<T: core::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        Error::Cfg(format!(
+            "Crypt option {key} needs a number, found '{value}'"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn parses_and_translates() {
+        let opts = parse_crypt_options(&[
+            "luks",
+            "discard",
+            "perf-no_read_workqueue",
+            "no-write-workqueue",
+            "header=/headers/root.img",
+            "key-slot=1",
+            "keyfile-offset=4",
+            "keyfile-size=8",
+            "readonly",
+            "nofail",
+            "tries=3",
+            "x-systemd.device-timeout=0",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                "--type=luks",
+                "--readonly",
+                "--allow-discards",
+                "--perf-no_read_workqueue",
+                "--perf-no_write_workqueue",
+                "--header=/headers/root.img",
+                "--key-slot=1",
+                "--keyfile-offset=4",
+                "--keyfile-size=8",
+            ],
+            opts.cryptsetup_args()
+        );
+        assert_eq!(b"45678901", opts.key_slice(b"0123456789012").unwrap());
+        assert!(opts.key_slice(b"0123456").is_err());
+        let plain =
+            parse_crypt_options(&["type=plain", "cipher=aes-xts-plain64", "size=512"]).unwrap();
+        assert_eq!(CryptType::Plain, plain.kind);
+        assert_eq!(Some(512), plain.key_size);
+        assert!(parse_crypt_options(&["luks", "plain"]).is_err());
+        assert!(parse_crypt_options(&["type=luks3"]).is_err());
+        assert!(parse_crypt_options(&["key-slot=one"]).is_err());
+        assert!(parse_crypt_options(&["header=headers/root.img"]).is_err());
+        assert!(parse_crypt_options(&["cipher=aes-xts-plain64"]).is_err());
+        assert!(parse_crypt_options(&["plain", "key-slot=0"]).is_err());
+        assert!(parse_crypt_options(&["allow-discard"]).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! Device-mapper through the `/dev/mapper/control` ioctls, see `linux/dm-ioctl.h`.
+//! Without udev nothing creates the `/dev/mapper/<name>` nodes, so that's done here too.
+use crate::error::{Error, Result};
+use crate::sys;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use tiny_std::unix::fd::AsRawFd;
+
+const CONTROL: &str = "/dev/mapper/control\0";
+
+/// The oldest interface version with everything used here.
+const DM_VERSION: [u32; 3] = [4, 0, 0];
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+const DM_MAX_TYPE_NAME: usize = 16;
+
+// `struct dm_ioctl` layout
+const OFF_VERSION: usize = 0;
+const OFF_DATA_SIZE: usize = 12;
+const OFF_DATA_START: usize = 16;
+const OFF_TARGET_COUNT: usize = 20;
+const OFF_FLAGS: usize = 28;
+const OFF_DEV: usize = 40;
+const OFF_NAME: usize = 48;
+const OFF_UUID: usize = OFF_NAME + DM_NAME_LEN;
+const DM_IOCTL_SIZE: usize = 312;
+
+// `struct dm_target_spec` layout, followed by the null terminated parameters
+const SPEC_OFF_START: usize = 0;
+const SPEC_OFF_LENGTH: usize = 8;
+const SPEC_OFF_NEXT: usize = 20;
+const SPEC_OFF_TYPE: usize = 24;
+const DM_TARGET_SPEC_SIZE: usize = 40;
+
+// `struct dm_name_list` layout, followed by the null terminated name
+const NAME_LIST_OFF_DEV: usize = 0;
+const NAME_LIST_OFF_NEXT: usize = 8;
+const NAME_LIST_OFF_NAME: usize = 12;
+
+const DM_LIST_DEVICES_CMD: usize = 2;
+const DM_DEV_CREATE_CMD: usize = 3;
+const DM_DEV_REMOVE_CMD: usize = 4;
+const DM_DEV_SUSPEND_CMD: usize = 6;
+const DM_TABLE_LOAD_CMD: usize = 9;
+
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_SUSPEND_FLAG: u32 = 1 << 1;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+/// Makes the kernel wipe its copies of the buffer, used for anything carrying a key
+const DM_SECURE_DATA_FLAG: u32 = 1 << 15;
+
+const fn dm_ioctl_request(cmd: usize) -> usize {
+    const IOC_READ_WRITE: usize = 3;
+    const DM_IOCTL: usize = 0xfd;
+    (IOC_READ_WRITE << 30) | (DM_IOCTL_SIZE << 16) | (DM_IOCTL << 8) | cmd
+}
+
+/// One line of a device-mapper table.
+pub struct Target {
+    /// In 512 byte sectors
+    pub start: u64,
+    /// In 512 byte sectors
+    pub length: u64,
+    pub target_type: String,
+    pub params: String,
+}
+
+impl Target {
+    /// A `crypt` target, `key` is hex encoded, `offset` is in sectors on `device`.
+    pub fn crypt(
+        length: u64,
+        cipher: &str,
+        key: &str,
+        device: &str,
+        offset: u64,
+        options: &[&str],
+    ) -> Self {
+        let mut params = format!("{cipher} {key} 0 {device} {offset}");
+        if !options.is_empty() {
+            params.push_str(&format!(" {}", options.len()));
+            for opt in options {
+                params.push(' ');
+                params.push_str(opt);
+            }
+        }
+        Self {
+            start: 0,
+            length,
+            target_type: "crypt".to_string(),
+            params,
+        }
+    }
+
+    /// A `linear` target mapping `length` sectors from `offset` in `device`.
+    pub fn linear(start: u64, length: u64, device: &str, offset: u64) -> Self {
+        Self {
+            start,
+            length,
+            target_type: "linear".to_string(),
+            params: format!("{device} {offset}"),
+        }
+    }
+
+    /// A format 1 `verity` target, see the kernel's `verity.rst`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn verity(
+        data_device: &str,
+        hash_device: &str,
+        data_block_size: u32,
+        hash_block_size: u32,
+        data_blocks: u64,
+        hash_start_block: u64,
+        algorithm: &str,
+        root_digest: &str,
+        salt: &str,
+    ) -> Self {
+        Self {
+            start: 0,
+            length: data_blocks * u64::from(data_block_size) / 512,
+            target_type: "verity".to_string(),
+            params: format!(
+                "1 {data_device} {hash_device} {data_block_size} {hash_block_size} {data_blocks} {hash_start_block} {algorithm} {root_digest} {salt}"
+            ),
+        }
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        // Crypt parameters contain the volume key
+        unsafe { self.params.as_bytes_mut().fill(0) };
+    }
+}
+
+/// An existing mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub name: String,
+    /// Kernel encoded device number
+    pub dev: u64,
+}
+
+impl Mapping {
+    pub fn major(&self) -> u64 {
+        ((self.dev >> 8) & 0xfff) | ((self.dev >> 32) & !0xfff)
+    }
+
+    pub fn minor(&self) -> u64 {
+        (self.dev & 0xff) | ((self.dev >> 12) & !0xff)
+    }
+}
+
+/// Creates `/dev/mapper/<name>` with `targets` as its live table.
+pub fn create(
+    name: &str,
+    uuid: Option<&str>,
+    targets: &[Target],
+    read_only: bool,
+) -> Result<Mapping> {
+    let mut create = DmIoctl::new(name, 0)?;
+    if let Some(uuid) = uuid {
+        create.set_uuid(uuid)?;
+    }
+    create.run(DM_DEV_CREATE_CMD)?;
+    let dev = create.dev();
+    let load = || -> Result<()> {
+        let mut load = DmIoctl::with_table(name, targets)?;
+        let mut flags = DM_SECURE_DATA_FLAG;
+        if read_only {
+            flags |= DM_READONLY_FLAG;
+        }
+        load.set_flags(flags);
+        load.run(DM_TABLE_LOAD_CMD)?;
+        // Resuming swaps the loaded table in
+        DmIoctl::new(name, 0)?.run(DM_DEV_SUSPEND_CMD)
+    };
+    if let Err(e) = load() {
+        // Don't leave an empty mapping behind, it would block retrying with the same name
+        let _ = DmIoctl::new(name, 0).and_then(|mut remove| remove.run(DM_DEV_REMOVE_CMD));
+        return Err(e);
+    }
+    let node = format!("/dev/mapper/{name}");
+    if !tiny_std::fs::exists(&node).unwrap_or(false) {
+        sys::mknod_block(&node, dev)
+            .map_err(|e| Error::Dm(format!("Failed to create device node {node}: {e:?}")))?;
+    }
+    Ok(Mapping {
+        name: name.to_string(),
+        dev,
+    })
+}
+
+/// Suspends I/O to `name`, queueing it until resumed or removed.
+pub fn suspend(name: &str) -> Result<()> {
+    let mut suspend = DmIoctl::new(name, 0)?;
+    suspend.set_flags(DM_SUSPEND_FLAG);
+    suspend.run(DM_DEV_SUSPEND_CMD)
+}
+
+/// Tears down `name` and its device node.
+pub fn remove(name: &str) -> Result<()> {
+    DmIoctl::new(name, 0)?.run(DM_DEV_REMOVE_CMD)?;
+    let node = format!("/dev/mapper/{name}");
+    if tiny_std::fs::exists(&node).unwrap_or(false) {
+        tiny_std::fs::remove_file(&node)
+            .map_err(|e| Error::Dm(format!("Failed to remove device node {node}: {e}")))?;
+    }
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<Mapping>> {
+    let mut data_len = 16 * 1024;
+    loop {
+        let mut list = DmIoctl::new("", data_len)?;
+        list.run(DM_LIST_DEVICES_CMD)?;
+        if list.flags() & DM_BUFFER_FULL_FLAG == 0 {
+            return parse_name_list(list.data());
+        }
+        data_len *= 2;
+    }
+}
+
+fn parse_name_list(data: &[u8]) -> Result<Vec<Mapping>> {
+    let mut mappings = Vec::new();
+    let mut offset = 0;
+    loop {
+        let entry = data
+            .get(offset..)
+            .filter(|e| e.len() > NAME_LIST_OFF_NAME)
+            .ok_or_else(|| Error::Dm(format!("Truncated dm name list at {offset}")))?;
+        let dev = read_u64(entry, NAME_LIST_OFF_DEV);
+        // No devices
+        if dev == 0 && offset == 0 {
+            return Ok(mappings);
+        }
+        let name = &entry[NAME_LIST_OFF_NAME..];
+        let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        let name = core::str::from_utf8(&name[..len])
+            .map_err(|e| Error::Dm(format!("dm name not utf8: {e}")))?;
+        mappings.push(Mapping {
+            name: name.to_string(),
+            dev,
+        });
+        let next = read_u32(entry, NAME_LIST_OFF_NEXT) as usize;
+        if next == 0 {
+            return Ok(mappings);
+        }
+        offset += next;
+    }
+}
+
+/// A `struct dm_ioctl` followed by its payload.
+struct DmIoctl {
+    buf: Vec<u8>,
+}
+
+impl DmIoctl {
+    fn new(name: &str, data_len: usize) -> Result<Self> {
+        if name.len() >= DM_NAME_LEN {
+            return Err(Error::Dm(format!(
+                "dm name {name} is longer than {}",
+                DM_NAME_LEN - 1
+            )));
+        }
+        let mut buf = vec![0u8; DM_IOCTL_SIZE + data_len];
+        for (ind, v) in DM_VERSION.iter().enumerate() {
+            write_u32(&mut buf, OFF_VERSION + ind * 4, *v);
+        }
+        let size = buf.len() as u32;
+        write_u32(&mut buf, OFF_DATA_SIZE, size);
+        write_u32(&mut buf, OFF_DATA_START, DM_IOCTL_SIZE as u32);
+        buf[OFF_NAME..OFF_NAME + name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self { buf })
+    }
+
+    fn with_table(name: &str, targets: &[Target]) -> Result<Self> {
+        let spec_len = |t: &Target| {
+            // Parameters are null terminated and each spec is 8 byte aligned
+            (DM_TARGET_SPEC_SIZE + t.params.len() + 1 + 7) & !7
+        };
+        let data_len = targets.iter().map(spec_len).sum();
+        let mut ioctl = Self::new(name, data_len)?;
+        write_u32(&mut ioctl.buf, OFF_TARGET_COUNT, targets.len() as u32);
+        let mut offset = DM_IOCTL_SIZE;
+        for target in targets {
+            if target.target_type.len() >= DM_MAX_TYPE_NAME {
+                return Err(Error::Dm(format!(
+                    "dm target type {} is too long",
+                    target.target_type
+                )));
+            }
+            let len = spec_len(target);
+            let spec = &mut ioctl.buf[offset..offset + len];
+            write_u64(spec, SPEC_OFF_START, target.start);
+            write_u64(spec, SPEC_OFF_LENGTH, target.length);
+            write_u32(spec, SPEC_OFF_NEXT, len as u32);
+            spec[SPEC_OFF_TYPE..SPEC_OFF_TYPE + target.target_type.len()]
+                .copy_from_slice(target.target_type.as_bytes());
+            spec[DM_TARGET_SPEC_SIZE..DM_TARGET_SPEC_SIZE + target.params.len()]
+                .copy_from_slice(target.params.as_bytes());
+            offset += len;
+        }
+        Ok(ioctl)
+    }
+
+    fn set_uuid(&mut self, uuid: &str) -> Result<()> {
+        if uuid.len() >= DM_UUID_LEN {
+            return Err(Error::Dm(format!(
+                "dm uuid {uuid} is longer than {}",
+                DM_UUID_LEN - 1
+            )));
+        }
+        self.buf[OFF_UUID..OFF_UUID + uuid.len()].copy_from_slice(uuid.as_bytes());
+        Ok(())
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        write_u32(&mut self.buf, OFF_FLAGS, flags);
+    }
+
+    fn flags(&self) -> u32 {
+        read_u32(&self.buf, OFF_FLAGS)
+    }
+
+    fn dev(&self) -> u64 {
+        read_u64(&self.buf, OFF_DEV)
+    }
+
+    fn data(&self) -> &[u8] {
+        let start = read_u32(&self.buf, OFF_DATA_START) as usize;
+        let end = (read_u32(&self.buf, OFF_DATA_SIZE) as usize).min(self.buf.len());
+        self.buf.get(start..end).unwrap_or_default()
+    }
+
+    fn run(&mut self, cmd: usize) -> Result<()> {
+        let control = tiny_std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(CONTROL)
+            .map_err(|e| Error::Dm(format!("Failed to open /dev/mapper/control: {e}")))?;
+        unsafe {
+            rusl::ioctl::ioctl(
+                control.as_raw_fd(),
+                dm_ioctl_request(cmd),
+                self.buf.as_mut_ptr() as usize,
+            )
+        }
+        .map_err(|e| Error::Dm(format!("dm ioctl {cmd} failed: {e}")))?;
+        Ok(())
+    }
+}
+
+impl Drop for DmIoctl {
+    fn drop(&mut self) {
+        // Table loads carry keys
+        self.buf.fill(0);
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, v: u32) {
+    buf[offset..offset + 4].copy_from_slice(&v.to_ne_bytes());
+}
+
+fn write_u64(buf: &mut [u8], offset: usize, v: u64) {
+    buf[offset..offset + 8].copy_from_slice(&v.to_ne_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_layout_and_name_list() {
+        let targets = [
+            Target::linear(0, 2048, "/dev/sda1", 0),
+            Target::crypt(
+                4096,
+                "aes-xts-plain64",
+                "00ff",
+                "/dev/sda2",
+                32768,
+                &["allow_discards"],
+            ),
+        ];
+        assert_eq!(
+            "aes-xts-plain64 00ff 0 /dev/sda2 32768 1 allow_discards",
+            targets[1].params
+        );
+        let load = DmIoctl::with_table("croot", &targets).unwrap();
+        assert_eq!(2, read_u32(&load.buf, OFF_TARGET_COUNT));
+        assert_eq!(load.buf.len() as u32, read_u32(&load.buf, OFF_DATA_SIZE));
+        let first = &load.buf[DM_IOCTL_SIZE..];
+        let next = read_u32(first, SPEC_OFF_NEXT) as usize;
+        assert_eq!(0, next % 8);
+        assert_eq!(&b"linear\0"[..], &first[SPEC_OFF_TYPE..SPEC_OFF_TYPE + 7]);
+        let second = &first[next..];
+        assert_eq!(4096, read_u64(second, SPEC_OFF_LENGTH));
+        assert_eq!(&b"crypt\0"[..], &second[SPEC_OFF_TYPE..SPEC_OFF_TYPE + 6]);
+        assert_eq!(0xc138_fd09, dm_ioctl_request(DM_TABLE_LOAD_CMD));
+
+        let mut list = vec![0u8; 64];
+        write_u64(&mut list, NAME_LIST_OFF_DEV, (253 << 8) | 1);
+        write_u32(&mut list, NAME_LIST_OFF_NEXT, 24);
+        list[NAME_LIST_OFF_NAME..NAME_LIST_OFF_NAME + 5].copy_from_slice(b"croot");
+        write_u64(&mut list[24..], NAME_LIST_OFF_DEV, (253 << 8) | 2);
+        list[24 + NAME_LIST_OFF_NAME..24 + NAME_LIST_OFF_NAME + 5].copy_from_slice(b"chome");
+        let mappings = parse_name_list(&list).unwrap();
+        assert_eq!(2, mappings.len());
+        assert_eq!("chome", mappings[1].name);
+        assert_eq!((253, 2), (mappings[1].major(), mappings[1].minor()));
+        assert!(parse_name_list(&[0u8; 16]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,649 @@
+//! Host side validation of a cfg against the machine it's going to boot.
+//! Meant to be run on the running system before generating an image, not in the initramfs.
+use crate::cfg::Swap;
+use crate::crypt_opts::{parse_crypt_options, CryptOptions};
+use crate::error::{Error, Result};
+use crate::luks::{check_header_checksum, read_header};
+use crate::lvm::{mapper_name, parse_lv};
+use crate::mountinfo::{find_mount, read_mountinfo, MountInfo};
+use crate::swap::{check_swap_file, resume_offset};
+use crate::sysfs::{dm_device_by_major_minor, dm_device_by_path, DmDevice};
+use crate::tab::{parse_crypttab, parse_fstab, CrypttabEntry, FstabEntry};
+use crate::Cfg;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use tiny_std::process::{Command, Stdio};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub status: Status,
+    pub check: String,
+    pub detail: String,
+}
+
+/// A crypt volume of the cfg, checked once however many mounts are on it.
+struct Volume<'a> {
+    role: &'a str,
+    uuid: &'a str,
+    key_file: Option<&'a str>,
+    /// The same options the volume is opened with, its detached header path is the same on the
+    /// host as in the image
+    options: CryptOptions,
+    header_sha256: Option<&'a str>,
+    /// The uuid in the detached header, [`Volume::uuid`] is the partition's then
+    header_uuid: Option<String>,
+}
+
+impl Volume<'_> {
+    fn header(&self) -> Option<&str> {
+        self.options.header.as_deref()
+    }
+
+    /// The uuid device-mapper knows the opened volume by.
+    fn luks_uuid(&self) -> &str {
+        self.header_uuid.as_deref().unwrap_or(self.uuid)
+    }
+
+    fn device(&self) -> String {
+        // Without a header on the device only the partition has a uuid
+        if self.header().is_some() {
+            format!("/dev/disk/by-partuuid/{}", self.uuid)
+        } else {
+            format!("/dev/disk/by-uuid/{}", self.uuid)
+        }
+    }
+}
+
+/// A mount or swap of the cfg, checked against how the host has it.
+struct Target<'a> {
+    name: &'a str,
+    /// `None` for swap
+    mount_point: Option<&'a str>,
+    on: On<'a>,
+}
+
+/// What a [`Target`] is on.
+enum On<'a> {
+    Volume(&'a Volume<'a>),
+    /// `vg/lv` and its `/dev/mapper` name
+    Lv(&'a str, String),
+    /// An array or a device path, only checked for being there
+    Device(&'a str),
+}
+
+impl On<'_> {
+    /// If the host has the target on `dm`, devices that aren't device-mapper's are taken as is.
+    fn is(&self, dm: Option<&DmDevice>) -> bool {
+        match self {
+            On::Volume(vol) => dm.and_then(DmDevice::luks_uuid).as_deref() == Some(vol.luks_uuid()),
+            On::Lv(_, mapper) => dm.is_some_and(|dm| &dm.name == mapper),
+            On::Device(_) => true,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            On::Volume(vol) => format!("the volume opened from {}", vol.luks_uuid()),
+            On::Lv(lv, mapper) => format!("logical volume {lv} (/dev/mapper/{mapper})"),
+            On::Device(device) => (*device).to_string(),
+        }
+    }
+}
+
+/// Runs all checks, a single failing check doesn't stop the others.
+pub fn doctor(cfg: &Cfg) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut report = |status: Status, check: String, detail: String| {
+        findings.push(Finding {
+            status,
+            check,
+            detail,
+        });
+    };
+    let mut readable_keys = Vec::new();
+    let key_files = cfg
+        .crypt
+        .iter()
+        .filter_map(|crypt| cfg.key_file_for(crypt))
+        .collect::<Vec<_>>();
+    for (ind, key_file) in key_files.iter().enumerate() {
+        if key_files[..ind].contains(key_file) {
+            continue;
+        }
+        match tiny_std::fs::read(key_file) {
+            Ok(key) if key.is_empty() => report(
+                Status::Fail,
+                "key file".to_string(),
+                format!("{key_file} is empty"),
+            ),
+            Ok(_) => {
+                report(
+                    Status::Ok,
+                    "key file".to_string(),
+                    format!("{key_file} is readable"),
+                );
+                readable_keys.push(*key_file);
+            }
+            Err(e) => report(
+                Status::Fail,
+                "key file".to_string(),
+                format!("Failed to read {key_file}: {e}"),
+            ),
+        }
+    }
+    let crypttab = read_tab("/etc/crypttab", parse_crypttab, &mut report);
+    let fstab = read_tab("/etc/fstab", parse_fstab, &mut report);
+    let mounts = match read_mountinfo() {
+        Ok(mounts) => mounts,
+        Err(e) => {
+            report(Status::Warn, "mountinfo".to_string(), format!("{e:?}"));
+            Vec::new()
+        }
+    };
+    // Every volume, also the ones under logical volumes, arrays and btrfs members
+    let volumes = volumes(cfg, &mut report);
+    for vol in &volumes {
+        check_volume(vol, &readable_keys, &mut report);
+        check_crypttab(vol, crypttab.as_deref(), &mut report);
+    }
+    for target in targets(cfg, &volumes) {
+        check_fstab(&target, crypttab.as_deref(), fstab.as_deref(), &mut report);
+        if let Some(mount_point) = target.mount_point {
+            check_mounted(&target, mount_point, &mounts, &mut report);
+        } else {
+            check_swap_active(&target, &mut report);
+        }
+    }
+    for swap in &cfg.swaps {
+        let mount = swap
+            .mount
+            .as_ref()
+            .and_then(|name| cfg.mounts.iter().find(|m| &m.name == name));
+        if let Some(mount) = mount {
+            check_swap_file_offset(swap, &mount.target, &mounts, &mut report);
+        }
+    }
+    findings
+}
+
+fn volumes<'a>(cfg: &'a Cfg, report: &mut impl FnMut(Status, String, String)) -> Vec<Volume<'a>> {
+    let mut volumes = Vec::new();
+    for crypt in &cfg.crypt {
+        let options = match parse_crypt_options(&crypt.options) {
+            Ok(options) => options,
+            Err(e) => {
+                report(
+                    Status::Fail,
+                    format!("{} options", crypt.name),
+                    format!("{e:?}"),
+                );
+                continue;
+            }
+        };
+        let header_uuid = options
+            .header
+            .as_deref()
+            .and_then(|h| read_header(h).ok())
+            .map(|h| h.uuid().to_string());
+        volumes.push(Volume {
+            role: &crypt.name,
+            uuid: &crypt.uuid,
+            key_file: cfg.key_file_for(crypt),
+            options,
+            header_sha256: crypt.header_sha256.as_deref(),
+            header_uuid,
+        });
+    }
+    volumes
+}
+
+/// Mounts and swaps that aren't swap files, those on a volume with invalid options are left out.
+fn targets<'a>(cfg: &'a Cfg, volumes: &'a [Volume<'a>]) -> Vec<Target<'a>> {
+    let on = |source: &'a str| {
+        if cfg.crypt.iter().any(|c| c.name == source) {
+            return volumes.iter().find(|v| v.role == source).map(On::Volume);
+        }
+        Some(match parse_lv(source) {
+            Some((vg, lv)) => On::Lv(source, mapper_name(vg, lv)),
+            None => On::Device(source),
+        })
+    };
+    let mounts = cfg.mounts.iter().filter_map(|m| {
+        Some(Target {
+            name: &m.name,
+            mount_point: Some(&m.target),
+            on: on(&m.source)?,
+        })
+    });
+    let swaps = cfg
+        .swaps
+        .iter()
+        .filter(|s| s.mount.is_none())
+        .filter_map(|s| {
+            Some(Target {
+                name: &s.name,
+                mount_point: None,
+                on: on(&s.source)?,
+            })
+        });
+    mounts.chain(swaps).collect()
+}
+
+/// The device is there and has a LUKS header, and the key unlocks it.
+fn check_volume(
+    vol: &Volume,
+    readable_keys: &[&str],
+    report: &mut impl FnMut(Status, String, String),
+) {
+    let device = vol.device();
+    if !tiny_std::fs::exists(&device).unwrap_or(false) {
+        report(
+            Status::Fail,
+            format!("{} device", vol.role),
+            format!("No device with uuid {} ({device} doesn't exist)", vol.uuid),
+        );
+        return;
+    }
+    let header = vol.header().unwrap_or(&device);
+    match read_header(header) {
+        Ok(luks) => report(
+            Status::Ok,
+            format!("{} device", vol.role),
+            format!("{header} is LUKS{}", luks.version()),
+        ),
+        Err(e) => {
+            report(
+                Status::Fail,
+                format!("{} device", vol.role),
+                format!("{e:?}"),
+            );
+            return;
+        }
+    }
+    if vol.header().is_some() {
+        check_header(vol, header, report);
+    }
+    if let Some(key_file) = vol.key_file.filter(|k| readable_keys.contains(k)) {
+        match test_open(&device, key_file, &vol.options) {
+            Ok(()) => report(
+                Status::Ok,
+                format!("{} key", vol.role),
+                format!("{key_file} unlocks {device}"),
+            ),
+            Err(e) => report(
+                Status::Fail,
+                format!("{} key", vol.role),
+                format!("{key_file} doesn't unlock {device}: {e:?}"),
+            ),
+        }
+    }
+}
+
+/// Swap files are checked as they would be when booting, and their resume offset calculated.
+fn check_swap_file_offset(
+    swap: &Swap,
+    mount_point: &str,
+    mounts: &[MountInfo],
+    report: &mut impl FnMut(Status, String, String),
+) {
+    let check = format!("swap.{} file", swap.name);
+    let path = &swap.source;
+    let extents = match check_swap_file(path) {
+        Ok(extents) => extents,
+        Err(e) => {
+            report(Status::Fail, check, format!("{e:?}"));
+            return;
+        }
+    };
+    // btrfs maps its own address space onto its devices, FIEMAP doesn't give device offsets
+    let btrfs = find_mount(mounts, mount_point).is_some_and(|m| m.fs_type == "btrfs");
+    let offset = resume_offset(&extents).filter(|_| !btrfs);
+    match (swap.resume_offset, offset) {
+        (None, Some(offset)) => report(
+            Status::Ok,
+            check,
+            format!("{path} can be swapped to, set swap.{}.resume_offset={offset} to resume from it", swap.name),
+        ),
+        (None, None) => report(
+            Status::Ok,
+            check,
+            format!("{path} can be swapped to"),
+        ),
+        (Some(want), Some(offset)) if want == offset => report(
+            Status::Ok,
+            check,
+            format!("{path} can be swapped to and starts at resume_offset {offset}"),
+        ),
+        (Some(want), Some(offset)) => report(
+            Status::Fail,
+            check,
+            format!("{path} starts at page {offset}, not at resume_offset {want}, set swap.{}.resume_offset={offset}", swap.name),
+        ),
+        (Some(want), None) => report(
+            Status::Warn,
+            check,
+            format!("{path} is on btrfs, compare resume_offset {want} with `btrfs inspect-internal map-swapfile -r {path}`"),
+        ),
+    }
+}
+
+fn check_header(vol: &Volume, header: &str, report: &mut impl FnMut(Status, String, String)) {
+    let check = format!("{} header", vol.role);
+    match vol.header_sha256 {
+        Some(sha256) => match check_header_checksum(header, sha256) {
+            Ok(()) => report(Status::Ok, check, format!("{header} matches header_sha256")),
+            Err(e) => report(Status::Fail, check, format!("{e:?}")),
+        },
+        None => report(
+            Status::Warn,
+            check,
+            format!(
+                "No crypt.{}.header_sha256 for {header}, a stale copy in the image won't be caught",
+                vol.role
+            ),
+        ),
+    }
+}
+
+fn read_tab<T>(
+    path: &str,
+    parse: fn(&str) -> Result<Vec<T>>,
+    report: &mut impl FnMut(Status, String, String),
+) -> Option<Vec<T>> {
+    let content = match tiny_std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            report(
+                Status::Warn,
+                path.to_string(),
+                format!("Failed to read {path}: {e}"),
+            );
+            return None;
+        }
+    };
+    match parse(&content) {
+        Ok(entries) => Some(entries),
+        Err(e) => {
+            report(Status::Warn, path.to_string(), format!("{e:?}"));
+            None
+        }
+    }
+}
+
+fn check_crypttab(
+    vol: &Volume,
+    crypttab: Option<&[CrypttabEntry]>,
+    report: &mut impl FnMut(Status, String, String),
+) {
+    let Some(crypttab) = crypttab else {
+        return;
+    };
+    match host_mapper(vol, crypttab) {
+        Some(name) => report(
+            Status::Ok,
+            format!("{} crypttab", vol.role),
+            format!("/etc/crypttab opens it as {name}"),
+        ),
+        None => report(
+            Status::Warn,
+            format!("{} crypttab", vol.role),
+            format!("No /etc/crypttab entry for UUID={}", vol.uuid),
+        ),
+    }
+}
+
+/// The mapper name the host uses for the volume.
+fn host_mapper<'a>(vol: &Volume, crypttab: &'a [CrypttabEntry]) -> Option<&'a str> {
+    crypttab
+        .iter()
+        .find(|e| e.uuid() == Some(vol.uuid))
+        .map(|e| e.name.as_str())
+}
+
+fn check_fstab(
+    target: &Target,
+    crypttab: Option<&[CrypttabEntry]>,
+    fstab: Option<&[FstabEntry]>,
+    report: &mut impl FnMut(Status, String, String),
+) {
+    let Some(fstab) = fstab else {
+        return;
+    };
+    // What fstab can name the device as, anything goes if unknown
+    let specs = match &target.on {
+        On::Volume(vol) => crypttab
+            .and_then(|c| host_mapper(vol, c))
+            .map(|m| alloc::vec![format!("/dev/mapper/{m}")])
+            .unwrap_or_default(),
+        On::Lv(lv, mapper) => alloc::vec![format!("/dev/mapper/{mapper}"), format!("/dev/{lv}")],
+        On::Device(_) => Vec::new(),
+    };
+    let entry = if let Some(mount_point) = target.mount_point {
+        fstab.iter().find(|e| e.file == mount_point)
+    } else {
+        fstab
+            .iter()
+            .find(|e| e.vfstype == "swap" && specs.contains(&e.spec))
+    };
+    let check = format!("{} fstab", target.name);
+    match entry {
+        None => report(
+            Status::Warn,
+            check,
+            format!(
+                "No /etc/fstab entry for {}",
+                target.mount_point.unwrap_or("swap")
+            ),
+        ),
+        Some(entry) if !specs.is_empty() && !specs.contains(&entry.spec) => report(
+            Status::Warn,
+            check,
+            format!(
+                "/etc/fstab mounts {} on {}, expected {}",
+                entry.spec,
+                entry.file,
+                specs.join(" or ")
+            ),
+        ),
+        Some(entry) => report(
+            Status::Ok,
+            check,
+            format!("/etc/fstab mounts {} on {}", entry.spec, entry.file),
+        ),
+    }
+}
+
+fn check_mounted(
+    target: &Target,
+    mount_point: &str,
+    mounts: &[MountInfo],
+    report: &mut impl FnMut(Status, String, String),
+) {
+    let check = format!("{} mount", target.name);
+    let Some(mount) = find_mount(mounts, mount_point) else {
+        report(
+            Status::Fail,
+            check,
+            format!("Nothing is mounted on {mount_point}"),
+        );
+        return;
+    };
+    let dm = dm_device_by_major_minor(&mount.major_minor)
+        .or_else(|| dm_device_by_path(&mount.source).ok().flatten());
+    report_backing(
+        target,
+        check,
+        &mount.source,
+        mount_point,
+        dm.as_ref(),
+        report,
+    );
+}
+
+fn check_swap_active(target: &Target, report: &mut impl FnMut(Status, String, String)) {
+    let check = format!("{} active", target.name);
+    let swaps = match tiny_std::fs::read_to_string("/proc/swaps") {
+        Ok(swaps) => swaps,
+        Err(e) => {
+            report(
+                Status::Warn,
+                check,
+                format!("Failed to read /proc/swaps: {e}"),
+            );
+            return;
+        }
+    };
+    // Filename  Type  Size  Used  Priority
+    for device in swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+    {
+        let dm = dm_device_by_path(device).ok().flatten();
+        let found = match &target.on {
+            On::Device(source) => device == *source,
+            on => on.is(dm.as_ref()),
+        };
+        if found {
+            report_backing(target, check, device, "swap", dm.as_ref(), report);
+            return;
+        }
+    }
+    report(
+        Status::Warn,
+        check,
+        format!("No active swap is on {}", target.on.describe()),
+    );
+}
+
+fn report_backing(
+    target: &Target,
+    check: String,
+    source: &str,
+    mount_point: &str,
+    dm: Option<&DmDevice>,
+    report: &mut impl FnMut(Status, String, String),
+) {
+    if target.on.is(dm) {
+        report(
+            Status::Ok,
+            check,
+            format!("{mount_point} is {source}, on {}", target.on.describe()),
+        );
+    } else {
+        report(
+            Status::Fail,
+            check,
+            format!(
+                "{mount_point} is {source}, but cfg has it on {}",
+                target.on.describe()
+            ),
+        );
+    }
+}
+
+/// Checks that the key unlocks the device without creating a mapping, with the same arguments
+/// it's opened with at boot.
+pub fn test_open(device: &str, key_file: &str, options: &CryptOptions) -> Result<()> {
+    let mut cmd = Command::new("/sbin/cryptsetup").map_err(|e| {
+        Error::Crypt(format!(
+            "Failed to instantiate command /sbin/cryptsetup {e}"
+        ))
+    })?;
+    let mut args = options.cryptsetup_args();
+    args.extend(
+        ["--key-file", key_file, "open", "--test-passphrase", device]
+            .into_iter()
+            .map(String::from),
+    );
+    for arg in &args {
+        cmd.arg(arg).map_err(|e| {
+            Error::Crypt(format!(
+                "Failed to instantiate command /sbin/cryptsetup adding arg {arg}: {e}"
+            ))
+        })?;
+    }
+    let res = cmd
+        .stdout(Stdio::Null)
+        .spawn()
+        .map_err(|e| Error::Crypt(format!("Failed to spawn /sbin/cryptsetup {e}")))?
+        .wait()
+        .map_err(|e| {
+            Error::Crypt(format!(
+                "Failed to await for child process /sbin/cryptsetup: {e}"
+            ))
+        })?;
+    if res != 0 {
+        return Err(Error::Crypt(format!(
+            "Got error from /sbin/cryptsetup, code {res}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::{CryptVolume, Mount, PseudoFs, Unlock};
+    use alloc::vec;
+
+    fn crypt(name: &str) -> CryptVolume {
+        CryptVolume {
+            name: name.to_string(),
+            uuid: String::new(),
+            key_file: None,
+            key_mount: None,
+            options: Vec::new(),
+            unlock: None,
+            header_sha256: None,
+        }
+    }
+
+    fn mount(name: &str, source: &str, target: &str) -> Mount {
+        Mount {
+            name: name.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            fs_type: None,
+            options: Vec::new(),
+            subvol: None,
+        }
+    }
+
+    #[test]
+    fn checks_every_volume_once() {
+        let cfg = Cfg {
+            key_file_path: Some("/key".to_string()),
+            unlock: Unlock::Cryptsetup,
+            pseudo_fs: PseudoFs::Unmount,
+            md: Vec::new(),
+            // Only under the logical volume, and two subvolumes
+            crypt: vec![crypt("cryptlvm"), crypt("cdata")],
+            mounts: vec![
+                mount("root", "vg0/root", "/"),
+                mount("data", "cdata", "/data"),
+                mount("snapshots", "cdata", "/data/.snapshots"),
+            ],
+            swaps: Vec::new(),
+            zram: Vec::new(),
+        };
+        let volumes = volumes(&cfg, &mut |_, _, _| panic!("Options are valid"));
+        assert_eq!(
+            vec!["cryptlvm", "cdata"],
+            volumes.iter().map(|v| v.role).collect::<Vec<_>>()
+        );
+        let targets = targets(&cfg, &volumes);
+        assert_eq!(3, targets.len());
+        assert!(matches!(&targets[0].on, On::Lv("vg0/root", mapper) if mapper == "vg0-root"));
+        assert!(matches!(targets[1].on, On::Volume(vol) if vol.role == "cdata"));
+        assert!(matches!(targets[2].on, On::Volume(vol) if vol.role == "cdata"));
+    }
+}
@@ -7,11 +7,21 @@ pub enum Error {
     App(String),
     Bail(String),
     Crypt(String),
+    Dm(String),
     FindPartitions(String),
+    Generate(String),
+    Json(String),
+    Lvm(String),
+    Luks(String),
+    Md(String),
+    MountInfo(String),
     MountPseudo(String),
     Mount(String),
     Cfg(String),
+    Resume(String),
     Spawn(String),
+    Swap(String),
+    Sysfs(String),
+    Tab(String),
     UnMount(String),
 }
-
@@ -0,0 +1,134 @@
+//! Filesystem detection from on-disk superblocks, and checking that the kernel can mount it.
+use crate::error::{Error, Result};
+use crate::sys::btrfs;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use core::time::Duration;
+use tiny_std::io::Read;
+
+/// Enough to cover the btrfs superblock at 64KiB.
+const PROBE_LEN: usize = 0x10000 + 0x1000;
+/// How long to wait for the members of a multi-device btrfs to show up.
+const BTRFS_READY_TIMEOUT: Duration = Duration::from_secs(10);
+const BTRFS_READY_POLL: Duration = Duration::from_millis(100);
+
+/// Reads the start of `device` and returns the kernel name of the filesystem on it.
+pub fn detect_filesystem(device: &str) -> Result<Option<&'static str>> {
+    let mut file = tiny_std::fs::File::open(device)
+        .map_err(|e| Error::Mount(format!("Failed to open {device} for fs detection: {e}")))?;
+    let mut buf = vec![0u8; PROBE_LEN];
+    let mut read = 0;
+    while read < buf.len() {
+        let n = file
+            .read(&mut buf[read..])
+            .map_err(|e| Error::Mount(format!("Failed to read superblock of {device}: {e}")))?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+    Ok(detect_from_superblock(&buf[..read]))
+}
+
+fn detect_from_superblock(buf: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| buf.get(offset..offset + magic.len()) == Some(magic);
+    if at(0x10040, b"_BHRfS_M") {
+        return Some("btrfs");
+    }
+    // Current and pre 1.0 bcachefs magic, after csum and versions
+    if at(
+        0x1018,
+        &[
+            0xc6, 0x85, 0x73, 0xf6, 0x66, 0xce, 0x90, 0xa9, 0xd9, 0x6a, 0x60, 0xcf, 0x80, 0x3d,
+            0xf7, 0xef,
+        ],
+    ) || at(
+        0x1018,
+        &[
+            0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca, 0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba,
+            0x6d, 0x81,
+        ],
+    ) {
+        return Some("bcachefs");
+    }
+    if at(0, b"XFSB") {
+        return Some("xfs");
+    }
+    if at(0x400, &0xF2F5_2010u32.to_le_bytes()) {
+        return Some("f2fs");
+    }
+    // The ext4 driver handles ext2 and ext3 as well
+    if at(0x438, &0xEF53u16.to_le_bytes()) {
+        return Some("ext4");
+    }
+    if at(0x1FE, &[0x55, 0xAA]) && (at(0x36, b"FAT") || at(0x52, b"FAT32")) {
+        return Some("vfat");
+    }
+    None
+}
+
+/// Registers every btrfs device among `candidates` with the kernel, then waits until all members
+/// of the filesystem on `source` are present so that it can be mounted without `degraded`.
+pub fn wait_for_btrfs_devices(source: &str, candidates: &[String]) -> Result<()> {
+    for candidate in candidates {
+        if candidate == source || detect_filesystem(candidate)? == Some("btrfs") {
+            btrfs::scan_device(candidate)?;
+        }
+    }
+    let mut waited = Duration::ZERO;
+    while !btrfs::devices_ready(source)? {
+        if waited >= BTRFS_READY_TIMEOUT {
+            return Err(Error::Mount(format!(
+                "Not all devices of the btrfs filesystem on {source} showed up within {}s",
+                BTRFS_READY_TIMEOUT.as_secs()
+            )));
+        }
+        tiny_std::thread::sleep(BTRFS_READY_POLL)
+            .map_err(|e| Error::Mount(format!("Failed to sleep waiting for btrfs devices: {e}")))?;
+        waited += BTRFS_READY_POLL;
+    }
+    Ok(())
+}
+
+/// Fails if `fs_type` isn't registered with the kernel, either built in or from a loaded module.
+pub fn ensure_kernel_support(fs_type: &str) -> Result<()> {
+    let filesystems = tiny_std::fs::read_to_string("/proc/filesystems")
+        .map_err(|e| Error::Mount(format!("Failed to read /proc/filesystems: {e}")))?;
+    if supports(&filesystems, fs_type) {
+        Ok(())
+    } else {
+        Err(Error::Mount(format!(
+            "Kernel has no {fs_type} driver (not in /proc/filesystems), it needs to be built in since the initramfs has no modules"
+        )))
+    }
+}
+
+fn supports(proc_filesystems: &str, fs_type: &str) -> bool {
+    // `nodev\tproc` or `\text4`
+    proc_filesystems
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .any(|fs| fs == fs_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_superblocks() {
+        let mut buf = vec![0u8; PROBE_LEN];
+        assert_eq!(None, detect_from_superblock(&buf));
+        buf[0x438..0x43A].copy_from_slice(&[0x53, 0xEF]);
+        assert_eq!(Some("ext4"), detect_from_superblock(&buf));
+        buf[0x10040..0x10048].copy_from_slice(b"_BHRfS_M");
+        assert_eq!(Some("btrfs"), detect_from_superblock(&buf));
+        // Truncated devices don't panic
+        assert_eq!(None, detect_from_superblock(&buf[..0x100]));
+        let proc_filesystems = "nodev\tsysfs\nnodev\tproc\n\text4\n\tvfat\nnodev\tbtrfs_fake\n";
+        assert!(supports(proc_filesystems, "ext4"));
+        assert!(supports(proc_filesystems, "proc"));
+        assert!(!supports(proc_filesystems, "btrfs"));
+    }
+}
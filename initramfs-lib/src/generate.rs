@@ -0,0 +1,314 @@
+//! Reconstructs a cfg from the LUKS layout of the running system. Mounts and swaps have to be on
+//! a LUKS volume, or on an LVM logical volume with its physical volumes on LUKS volumes.
+use crate::error::{Error, Result};
+use crate::lvm::lv_from_device;
+use crate::mountinfo::{find_mount, read_mountinfo};
+use crate::sysfs::{dm_device_by_major_minor, dm_device_by_path, DmDevice};
+use crate::tab::{parse_crypttab, parse_fstab, CrypttabEntry, FstabEntry};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// What a mount or swap is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Backing {
+    /// A LUKS volume's uuid
+    Luks(String),
+    /// `vg/lv` and the mapper names and uuids of the LUKS volumes under its physical volumes
+    Lv(String, Vec<(String, String)>),
+}
+
+impl Backing {
+    fn luks_uuids(&self) -> Vec<&str> {
+        match self {
+            Backing::Luks(uuid) => alloc::vec![uuid.as_str()],
+            Backing::Lv(_, pvs) => pvs.iter().map(|(_, uuid)| uuid.as_str()).collect(),
+        }
+    }
+}
+
+/// What a mount or swap was found on, `via` is kept for the generated comments.
+struct Found {
+    backing: Backing,
+    /// The btrfs subvolume that's mounted, `None` for the top level
+    subvol: Option<String>,
+    via: String,
+}
+
+/// Generates cfg file content for the current machine. `key_file_path` defaults to the key file
+/// crypttab uses for root. Only root is required, `/home` and swap are left out if they aren't on
+/// LUKS.
+pub fn generate_cfg(key_file_path: Option<&str>) -> Result<String> {
+    let crypttab = read_optional("/etc/crypttab")?
+        .map(|c| parse_crypttab(&c))
+        .transpose()?
+        .unwrap_or_default();
+    let fstab = read_optional("/etc/fstab")?
+        .map(|c| parse_fstab(&c))
+        .transpose()?
+        .unwrap_or_default();
+    let mounts = read_mountinfo()?;
+    let resolve_mount = |mount_point: &str| -> Result<Option<Found>> {
+        if let Some(mount) = find_mount(&mounts, mount_point) {
+            let dm = match dm_device_by_major_minor(&mount.major_minor) {
+                Some(dm) => Some(dm),
+                None => dm_device_by_path(&mount.source)?,
+            };
+            if let Some(dm) = dm {
+                let subvol = (mount.fs_type == "btrfs" && mount.root != "/")
+                    .then(|| mount.root.trim_start_matches('/').to_string());
+                return Ok(Some(Found {
+                    backing: backing(&dm)?,
+                    subvol,
+                    via: format!("{} mounted on {mount_point}", mount.source),
+                }));
+            }
+        }
+        let entry = fstab.iter().find(|e| e.file == mount_point);
+        Ok(from_fstab(entry, &crypttab))
+    };
+    let root = resolve_mount("/")?.ok_or_else(|| {
+        Error::Generate(
+            "/ is neither mounted from a LUKS volume nor in /etc/fstab through one in /etc/crypttab"
+                .to_string(),
+        )
+    })?;
+    let home = resolve_mount("/home")?;
+    let swap = resolve_swap(&fstab, &crypttab)?;
+    let key_file_path = match key_file_path {
+        Some(key) => key.to_string(),
+        None => crypttab
+            .iter()
+            .find(|e| {
+                e.uuid()
+                    .is_some_and(|u| root.backing.luks_uuids().contains(&u))
+            })
+            .and_then(|e| e.key_file.clone())
+            .ok_or_else(|| {
+                Error::Generate("No key file given and /etc/crypttab has none for root".to_string())
+            })?,
+    };
+    Ok(render(&key_file_path, &root, home.as_ref(), swap.as_ref()))
+}
+
+/// Shorthands for LUKS volumes that hold a single top level filesystem, spelled out entries for
+/// logical volumes, subvolumes and LUKS volumes shared by several of them.
+fn render(key_file_path: &str, root: &Found, home: Option<&Found>, swap: Option<&Found>) -> String {
+    let mut out = String::from("// Generated from the running system\n");
+    let roles = [
+        ("root", "/", Some(root)),
+        ("swap", "", swap),
+        ("home", "/home", home),
+    ];
+    let mut volumes: Vec<(String, &str)> = Vec::new();
+    for (role, target, found) in roles {
+        let Some(found) = found else {
+            out.push_str(&format!("// {role}: not on a LUKS volume\n"));
+            continue;
+        };
+        out.push_str(&format!("// {role}: {}\n", found.via));
+        let source = match &found.backing {
+            Backing::Luks(uuid) => {
+                let shared = roles
+                    .iter()
+                    .filter_map(|(_, _, f)| f.as_ref())
+                    .filter(|f| f.backing == found.backing)
+                    .count()
+                    > 1;
+                if !shared && found.subvol.is_none() {
+                    out.push_str(&format!("{role}={uuid}\n"));
+                    continue;
+                }
+                // Named after the first role on it, the same name as the shorthand's
+                match volumes.iter().find(|(_, u)| u == uuid) {
+                    Some((name, _)) => name.clone(),
+                    None => {
+                        let name = format!("c{role}");
+                        out.push_str(&format!("crypt.{name}.uuid={uuid}\n"));
+                        volumes.push((name.clone(), uuid));
+                        name
+                    }
+                }
+            }
+            Backing::Lv(lv, pvs) => {
+                for (name, uuid) in pvs {
+                    if !volumes.iter().any(|(n, u)| n == name && u == uuid) {
+                        volumes.push((name.clone(), uuid));
+                        out.push_str(&format!("crypt.{name}.uuid={uuid}\n"));
+                    }
+                }
+                lv.clone()
+            }
+        };
+        if role == "swap" {
+            out.push_str(&format!("swap.swap.source={source}\n"));
+            continue;
+        }
+        out.push_str(&format!(
+            "mount.{role}.source={source}\nmount.{role}.target={target}\n"
+        ));
+        if let Some(subvol) = &found.subvol {
+            out.push_str(&format!("mount.{role}.subvol={subvol}\n"));
+        }
+    }
+    out.push_str(&format!("key_file_path={key_file_path}\n"));
+    out
+}
+
+fn resolve_swap(fstab: &[FstabEntry], crypttab: &[CrypttabEntry]) -> Result<Option<Found>> {
+    let swaps = tiny_std::fs::read_to_string("/proc/swaps")
+        .map_err(|e| Error::Generate(format!("Failed to read /proc/swaps: {e}")))?;
+    // Filename  Type  Size  Used  Priority
+    for device in swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+    {
+        if let Some(dm) = dm_device_by_path(device)? {
+            return Ok(Some(Found {
+                backing: backing(&dm)?,
+                subvol: None,
+                via: format!("active swap {device}"),
+            }));
+        }
+    }
+    let entry = fstab.iter().find(|e| e.vfstype == "swap");
+    Ok(from_fstab(entry, crypttab))
+}
+
+/// Follows `/dev/mapper/<name>` in fstab to the crypttab entry of the same name.
+fn from_fstab(entry: Option<&FstabEntry>, crypttab: &[CrypttabEntry]) -> Option<Found> {
+    let entry = entry?;
+    let name = entry.spec.strip_prefix("/dev/mapper/")?;
+    let crypt = crypttab.iter().find(|c| c.name == name)?;
+    let subvol = entry
+        .options
+        .iter()
+        .find_map(|opt| opt.strip_prefix("subvol="))
+        .map(|subvol| subvol.trim_start_matches('/').to_string());
+    Some(Found {
+        backing: Backing::Luks(crypt.uuid()?.to_string()),
+        subvol,
+        via: format!(
+            "{} on {} in /etc/fstab, {} in /etc/crypttab",
+            entry.spec, entry.file, crypt.device
+        ),
+    })
+}
+
+/// A LUKS volume, or a logical volume whose physical volumes are all LUKS volumes. Anything else
+/// can't be unlocked by the init.
+fn backing(dm: &DmDevice) -> Result<Backing> {
+    if let Some(uuid) = dm.luks_uuid() {
+        return Ok(Backing::Luks(uuid));
+    }
+    let unsupported = |why: String| {
+        Error::Generate(format!(
+            "/dev/mapper/{} {why}, only LUKS volumes and LVM logical volumes on LUKS volumes can be generated",
+            dm.name
+        ))
+    };
+    if !dm.uuid.starts_with("LVM-") {
+        return Err(unsupported(format!(
+            "is a {} device-mapper device",
+            dm.uuid
+        )));
+    }
+    let lv = lv_from_device(&format!("/dev/mapper/{}", dm.name))
+        .ok_or_else(|| unsupported("isn't named like a logical volume".to_string()))?;
+    let mut pvs = Vec::new();
+    for slave in slaves(dm)? {
+        let luks = dm_device_by_path(&format!("/dev/{slave}"))?
+            .and_then(|pv| Some((pv.name.clone(), pv.luks_uuid()?)))
+            .ok_or_else(|| {
+                unsupported(format!(
+                    "is a logical volume on /dev/{slave}, which isn't a LUKS volume"
+                ))
+            })?;
+        pvs.push(luks);
+    }
+    if pvs.is_empty() {
+        return Err(unsupported("has no physical volumes".to_string()));
+    }
+    Ok(Backing::Lv(lv, pvs))
+}
+
+/// Kernel names of the devices under `dm`.
+fn slaves(dm: &DmDevice) -> Result<Vec<String>> {
+    let slaves_dir = format!("/sys/class/block/{}/slaves", dm.kernel_name);
+    let dir = tiny_std::fs::Directory::open(slaves_dir.as_str())
+        .map_err(|e| Error::Generate(format!("Failed to open {slaves_dir}: {e}")))?;
+    let mut slaves = Vec::new();
+    for entry in dir.read() {
+        let entry =
+            entry.map_err(|e| Error::Generate(format!("Failed to read {slaves_dir}: {e}")))?;
+        if entry.is_relative_reference() {
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .map_err(|e| Error::Generate(format!("Bad entry in {slaves_dir}: {e}")))?;
+        slaves.push(name.to_string());
+    }
+    Ok(slaves)
+}
+
+fn read_optional(path: &str) -> Result<Option<String>> {
+    match tiny_std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.matches_errno(rusl::error::Errno::ENOENT) => Ok(None),
+        Err(e) => Err(Error::Generate(format!("Failed to read {path}: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const ROOT: &str = "7e127144-f930-4656-84b4-d6a27fa7caa6";
+    const HOME: &str = "d2059da0-7eae-4396-badf-e6ed0ccfd04b";
+
+    fn found(backing: Backing) -> Found {
+        Found {
+            backing,
+            subvol: None,
+            via: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn renders_volumes() {
+        let cfg = render("/key", &found(Backing::Luks(ROOT.to_string())), None, None);
+        assert_eq!(
+            format!("// Generated from the running system\n// root: test\nroot={ROOT}\n// swap: not on a LUKS volume\n// home: not on a LUKS volume\nkey_file_path=/key\n"),
+            cfg
+        );
+        let lv = |lv: &str| {
+            Backing::Lv(
+                lv.to_string(),
+                vec![("cryptlvm".to_string(), HOME.to_string())],
+            )
+        };
+        let cfg = render(
+            "/key",
+            &found(lv("vg0/root")),
+            Some(&found(lv("vg0/home"))),
+            Some(&found(lv("vg0/swap"))),
+        );
+        assert_eq!(1, cfg.matches("crypt.cryptlvm.uuid=").count());
+        assert!(cfg.contains("mount.root.source=vg0/root\nmount.root.target=/\n"));
+        assert!(cfg.contains("mount.home.source=vg0/home\nmount.home.target=/home\n"));
+        assert!(cfg.contains("swap.swap.source=vg0/swap\n"));
+        // @ and @home in one LUKS volume
+        let subvol = |subvol: &str| Found {
+            subvol: Some(subvol.to_string()),
+            ..found(Backing::Luks(ROOT.to_string()))
+        };
+        let cfg = render("/key", &subvol("@"), Some(&subvol("@home")), None);
+        assert_eq!(
+            format!("// Generated from the running system\n// root: test\ncrypt.croot.uuid={ROOT}\nmount.root.source=croot\nmount.root.target=/\nmount.root.subvol=@\n// swap: not on a LUKS volume\n// home: test\nmount.home.source=croot\nmount.home.target=/home\nmount.home.subvol=@home\nkey_file_path=/key\n"),
+            cfg
+        );
+    }
+}
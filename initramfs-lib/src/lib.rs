@@ -1,16 +1,42 @@
 #![no_std]
 
+pub use crate::cfg::{is_uuid, read_cfg, Cfg};
+use crate::cfg::{CryptVolume, Mount, PseudoFs, Unlock};
+use crate::crypt_opts::{parse_crypt_options, CryptOptions};
 use crate::error::{Error, Result};
+use crate::mount_opts::parse_mount_options;
+use crate::plan::{Planned, Step};
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use alloc::{format, vec};
 use rusl::error::Errno;
 use rusl::platform::FilesystemType;
 use rusl::unistd::{mount, swapon, unmount};
 use tiny_std::io::{Read, Write};
-use tiny_std::process::{Command, Stdio};
+use tiny_std::process::{Child, Command, Stdio};
 use unix_print::{unix_eprintln, unix_println};
 
+pub mod cfg;
+pub mod crypt_opts;
+pub mod dm;
+pub mod doctor;
 mod error;
+pub mod fs;
+pub mod generate;
+pub mod json;
+pub mod luks;
+pub mod lvm;
+pub mod md;
+pub mod mount_opts;
+pub mod mountinfo;
+pub mod plan;
+pub mod resume;
+pub mod swap;
+mod sys;
+pub mod sysfs;
+pub mod tab;
+pub mod unlock;
+pub mod zram;
 
 extern crate alloc;
 
@@ -24,7 +50,12 @@ pub fn full_init(cfg: &Cfg) -> Result<()> {
     mount_user_filesystems(cfg)
         .map_err(|e| Error::App(format!("Failed to mount user filesystems {e:?}")))?;
     unix_println!("Cleaning up.");
-    try_unmount().map_err(|e| Error::App(format!("Failed to unmount pseudo filesystems {e:?}")))?;
+    match cfg.pseudo_fs {
+        PseudoFs::Unmount => try_unmount()
+            .map_err(|e| Error::App(format!("Failed to unmount pseudo filesystems {e:?}")))?,
+        PseudoFs::Move => move_pseudo_filesystems()
+            .map_err(|e| Error::App(format!("Failed to move pseudo filesystems {e:?}")))?,
+    }
     unix_println!("Done, switching root");
     let e = switch_root();
     Err(e)
@@ -41,49 +72,350 @@ pub fn mount_pseudo_filesystems() -> Result<()> {
 }
 
 pub fn mount_user_filesystems(cfg: &Cfg) -> Result<()> {
-    let parts = get_partitions(cfg)
-        .map_err(|e| Error::Mount(format!("Failed to find partitions {e:?}")))?;
-    let pass = tiny_std::fs::read(&cfg.key_file_path).map_err(|e| {
-        Error::Crypt(format!(
-            "Failed to read key file at {}: {e}",
-            cfg.key_file_path
-        ))
-    })?;
-    open_cryptodisk(&parts.root, "croot", &pass)
-        .map_err(|e| Error::Mount(format!("Failed to decrypt root partition {e:?}")))?;
-    open_cryptodisk(&parts.swap, "cswap", &pass)
-        .map_err(|e| Error::Mount(format!("Failed to decrypt swap partition {e:?}")))?;
-    open_cryptodisk(&parts.home, "chome", &pass)
-        .map_err(|e| Error::Mount(format!("Failed to decrypt home partition {e:?}")))?;
-    mount::<_, _, &'static str>(
-        "/dev/mapper/croot",
-        "/mnt/root\0",
-        FilesystemType::Ext4,
-        0,
-        None,
-    )
+    let planned = plan::plan(cfg)?;
+    let mut blkid = None;
+    // Any unlocked volume could be a member of a multi-device btrfs
+    let mut btrfs_candidates = Vec::with_capacity(cfg.crypt.len());
+    let mut skipped: Vec<(Step, Error)> = Vec::new();
+    let mut mounted = false;
+    let mut rest = planned.as_slice();
+    while !rest.is_empty() {
+        // Volumes never depend on each other, the ones next to each other in the plan are unlocked
+        // at the same time so that their KDFs run in parallel
+        let volumes = rest
+            .iter()
+            .take_while(|p| matches!(p.step, Step::Unlock(_)))
+            .count();
+        let (batch, next) = rest.split_at(volumes.max(1));
+        rest = next;
+        let results = if volumes > 0 {
+            unlock_volumes(cfg, batch, &skipped, &mut blkid, &mut btrfs_candidates)
+        } else {
+            vec![check_dependencies(&batch[0], &skipped)
+                .and_then(|()| run_step(&batch[0], &skipped, &btrfs_candidates, mounted))]
+        };
+        for (planned, res) in batch.iter().zip(results) {
+            let step = planned.step;
+            match res {
+                Ok(()) => mounted |= matches!(step, Step::Mount(_)),
+                Err(e) if step.is_optional() => {
+                    unix_eprintln!("Skipping optional {}: {e:?}", step.describe());
+                    skipped.push((step, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    if !skipped.is_empty() {
+        report_skipped(&skipped);
+    }
+    Ok(())
+}
+
+fn check_dependencies(planned: &Planned, skipped: &[(Step, Error)]) -> Result<()> {
+    match skipped.iter().find(|(s, _)| planned.after.contains(s)) {
+        Some((dep, _)) => Err(Error::Mount(format!(
+            "{} depends on skipped {}",
+            planned.step.describe(),
+            dep.describe()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Everything but unlocking, which goes through [`unlock_volumes`]. `mounted` is whether any
+/// filesystem is mounted yet.
+fn run_step(
+    planned: &Planned,
+    skipped: &[(Step, Error)],
+    btrfs_candidates: &[String],
+    mounted: bool,
+) -> Result<()> {
+    match planned.step {
+        Step::Assemble(array) => md::assemble(array).map_err(|e| {
+            Error::Mount(format!("Failed to assemble md array {}: {e:?}", array.name))
+        }),
+        Step::Unlock(volume) => Err(Error::Mount(format!(
+            "Volume {} has to be unlocked as part of a batch",
+            volume.name
+        ))),
+        Step::Activate(vg) => lvm::activate(&[vg]).or_else(|e| {
+            // Only skipped volumes that could be its physical volumes explain a failure
+            let missing = skipped
+                .iter()
+                .filter(|(s, _)| planned.waits_for.contains(s))
+                .map(|(s, _)| s.describe())
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                return Err(Error::Mount(format!(
+                    "Failed to activate volume group {vg} {e:?}"
+                )));
+            }
+            // Logical volumes that are complete are still activated
+            unix_eprintln!(
+                "Volume group {vg} is partially active, physical volumes may be on skipped {}: {e:?}",
+                missing.join(", ")
+            );
+            Ok(())
+        }),
+        Step::Mount(mnt) => mount_filesystem(mnt, btrfs_candidates),
+        Step::Swapon(swap) => {
+            let source = if swap.mount.is_some() {
+                let path = cfg::target_path(&swap.source);
+                swap::check_swap_file(&path)?;
+                path
+            } else {
+                let source = cfg::source_device(&swap.source);
+                resume_or_discard(&source, None, mounted)?;
+                source
+            };
+            // Validated when reading the cfg
+            let flags = swap::parse_swap_options(&swap.options)?.flags();
+            swapon(source.as_str(), flags)
+                .map_err(|e| Error::Mount(format!("Failed to swapon {source}: {e:?}")))
+        }
+        Step::Resume(swap, mount) => resume_or_discard(
+            &cfg::source_device(&mount.source),
+            swap.resume_offset,
+            mounted,
+        ),
+        Step::Zram(zram) => {
+            let device = zram::create(zram)?;
+            let flags = zram.priority.map_or(0, swap::priority_flags);
+            swapon(device.as_str(), flags)
+                .map_err(|e| Error::Swap(format!("Failed to swapon {device}: {e:?}")))
+        }
+    }
+}
+
+/// Resumes from a hibernation image on the swap at `source` unless `noresume` is given. If the
+/// kernel doesn't resume, the image is discarded so that the swap can be turned on. A swap file
+/// starts `offset` pages into `source`.
+fn resume_or_discard(source: &str, offset: Option<u64>, mounted: bool) -> Result<()> {
+    let at = offset.unwrap_or(0) * swap::PAGE_SIZE;
+    if resume::swap_signature(source, at)? != resume::SwapSignature::Hibernation {
+        return Ok(());
+    }
+    let resume = resume::resume_enabled()?;
+    if resume && mounted {
+        // The image expects the filesystems as they were when hibernating
+        return Err(Error::Resume(format!(
+            "{source} holds a hibernation image but filesystems are already mounted, not resuming from or discarding it, boot with noresume to discard it"
+        )));
+    }
+    if resume {
+        unix_println!("Resuming from hibernation image on {source}.");
+        let e = resume::resume(source, offset);
+        unix_eprintln!("Failed to resume from {source}: {e:?}");
+    } else {
+        unix_println!("Got noresume, discarding hibernation image on {source}.");
+    }
+    resume::discard_image(source, at)
+}
+
+/// Starts unlocking every volume of `batch` before waiting for any of them, each volume gets its
+/// own result.
+fn unlock_volumes(
+    cfg: &Cfg,
+    batch: &[Planned],
+    skipped: &[(Step, Error)],
+    blkid: &mut Option<String>,
+    btrfs_candidates: &mut Vec<String>,
+) -> Vec<Result<()>> {
+    let mut started = Vec::with_capacity(batch.len());
+    for planned in batch {
+        let Step::Unlock(volume) = planned.step else {
+            started.push(Err(Error::Mount(format!(
+                "{} isn't a volume to unlock",
+                planned.step.describe()
+            ))));
+            continue;
+        };
+        started.push(
+            check_dependencies(planned, skipped).and_then(|()| start_unlock(cfg, volume, blkid)),
+        );
+    }
+    started
+        .into_iter()
+        .map(|started| {
+            let (part, unlocking) = started?;
+            unlocking.wait().map_err(|e| {
+                Error::Mount(format!(
+                    "Failed to decrypt {} partition {}: {e:?}",
+                    part.name, part.device
+                ))
+            })?;
+            btrfs_candidates.push(cfg::source_device(&part.name));
+            Ok(())
+        })
+        .collect()
+}
+
+/// A volume that's being unlocked in a child process.
+enum Unlocking {
+    Cryptsetup(Child),
+    /// A fork running [`unlock::open`]
+    #[cfg(not(feature = "threaded"))]
+    Native(i32),
+    /// Unlocked natively without forking
+    #[cfg(feature = "threaded")]
+    Unlocked,
+}
+
+impl Unlocking {
+    fn wait(self) -> Result<()> {
+        match self {
+            Unlocking::Cryptsetup(mut child) => {
+                let res = child.wait().map_err(|e| {
+                    Error::Crypt(format!(
+                        "Failed to await for child process /sbin/cryptsetup: {e}"
+                    ))
+                })?;
+                if res != 0 {
+                    return Err(Error::Crypt(format!(
+                        "Got error from /sbin/cryptsetup, code {res}"
+                    )));
+                }
+            }
+            #[cfg(not(feature = "threaded"))]
+            Unlocking::Native(pid) => {
+                let res = rusl::process::wait_pid(pid, rusl::platform::WaitPidFlags::empty())
+                    .map_err(|e| {
+                        Error::Crypt(format!("Failed to await native unlock process {pid}: {e}"))
+                    })?;
+                if res.status != 0 {
+                    return Err(Error::Crypt(format!(
+                        "Native unlock process {pid} failed with status {}, its error is printed above",
+                        res.status
+                    )));
+                }
+            }
+            #[cfg(feature = "threaded")]
+            Unlocking::Unlocked => {}
+        }
+        Ok(())
+    }
+}
+
+fn start_unlock(
+    cfg: &Cfg,
+    volume: &CryptVolume,
+    blkid: &mut Option<String>,
+) -> Result<(Partition, Unlocking)> {
+    // Arrays are assembled before any volume, so blkid sees volumes on them
+    if blkid.is_none() {
+        *blkid = Some(
+            run_blkid().map_err(|e| Error::Mount(format!("Failed to find partitions {e:?}")))?,
+        );
+    }
+    let part = find_partition(blkid.as_deref().unwrap_or_default(), volume)?;
+    // Validated when reading the cfg
+    let key_file_path = cfg
+        .key_path_for(volume)
+        .ok_or_else(|| Error::Crypt(format!("No key file configured for {}", volume.name)))?;
+    let pass = tiny_std::fs::read(&key_file_path)
+        .map_err(|e| Error::Crypt(format!("Failed to read key file at {key_file_path}: {e}")))?;
+    // Validated when reading the cfg
+    let options = parse_crypt_options(&volume.options)?;
+    if let (Some(header), Some(sha256)) = (&options.header, &volume.header_sha256) {
+        luks::check_header_checksum(header, sha256)?;
+    }
+    let unlocking = match cfg.unlock_for(volume) {
+        Unlock::Cryptsetup => {
+            spawn_cryptsetup(&part.device, &part.name, &pass, &options).map(Unlocking::Cryptsetup)
+        }
+        Unlock::Native => native_unlock(&part, &pass, &options),
+    }
     .map_err(|e| {
         Error::Mount(format!(
-            "Failed to mount root partition {} to /mnt/root: {e:?}",
-            parts.root
+            "Failed to start decrypting {} partition {}: {e:?}",
+            part.name, part.device
         ))
     })?;
-    mount::<_, _, &'static str>(
-        "/dev/mapper/chome",
-        "/mnt/root/home\0",
-        FilesystemType::Ext4,
-        0,
-        None,
-    )
-    .map_err(|e| {
+    Ok((part, unlocking))
+}
+
+/// Unlocks in a forked child so that volumes unlock in parallel.
+#[cfg(not(feature = "threaded"))]
+fn native_unlock(part: &Partition, pass: &[u8], options: &CryptOptions) -> Result<Unlocking> {
+    // Safety: without the threaded feature the init is single threaded, nothing can hold a lock
+    // the child needs. It only unlocks and exits
+    let pid = unsafe { rusl::process::fork() }
+        .map_err(|e| Error::Crypt(format!("Failed to fork for native unlock: {e}")))?;
+    if pid == 0 {
+        let code = match unlock::open(&part.device, &part.name, pass, options) {
+            Ok(()) => 0,
+            Err(e) => {
+                unix_eprintln!("Failed to unlock {} on {}: {e:?}", part.name, part.device);
+                1
+            }
+        };
+        rusl::process::exit(code);
+    }
+    Ok(Unlocking::Native(pid))
+}
+
+/// A fork only has the thread that forked, if another one held the allocator's lock the child
+/// would deadlock on its first allocation. Volumes are unlocked one after the other instead.
+#[cfg(feature = "threaded")]
+fn native_unlock(part: &Partition, pass: &[u8], options: &CryptOptions) -> Result<Unlocking> {
+    unlock::open(&part.device, &part.name, pass, options).map(|()| Unlocking::Unlocked)
+}
+
+/// Skipped optional volumes end up in the kernel log, where the booted system can find them.
+fn report_skipped(skipped: &[(Step, Error)]) {
+    let mut kmsg = match tiny_std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/kmsg")
+    {
+        Ok(kmsg) => kmsg,
+        Err(e) => {
+            unix_eprintln!("Failed to open /dev/kmsg to report skipped volumes: {e}");
+            return;
+        }
+    };
+    for (step, e) in skipped {
+        // Warning level
+        let line = format!("<4>yubi-initramfs: skipped {}: {e:?}\n", step.describe());
+        if let Err(e) = kmsg.write_all(line.as_bytes()) {
+            unix_eprintln!("Failed to write skipped volumes to /dev/kmsg: {e}");
+            return;
+        }
+    }
+}
+
+fn mount_filesystem(mnt: &Mount, btrfs_candidates: &[String]) -> Result<()> {
+    let source = cfg::source_device(&mnt.source);
+    let target = cfg::target_path(&mnt.target);
+    let fs_type = match mnt.fs_type.as_deref() {
+        Some(fs_type) => fs_type,
+        None => fs::detect_filesystem(&source)?.ok_or_else(|| {
+            Error::Mount(format!(
+                "Failed to detect filesystem on {source} for mount {}, specify it in the cfg",
+                mnt.name
+            ))
+        })?,
+    };
+    fs::ensure_kernel_support(fs_type)?;
+    if fs_type == "btrfs" {
+        fs::wait_for_btrfs_devices(&source, btrfs_candidates)?;
+    }
+    // Validated when reading the cfg
+    let mut opts = parse_mount_options(&mnt.options)?;
+    if let Some(subvol) = &mnt.subvol {
+        let data = opts.data.get_or_insert_with(String::new);
+        if !data.is_empty() {
+            data.push(',');
+        }
+        data.push_str("subvol=");
+        data.push_str(subvol);
+    }
+    sys::mount(&source, &target, fs_type, opts.flags, opts.data.as_deref()).map_err(|e| {
         Error::Mount(format!(
-            "Failed to mount home partition {} to /mnt/root/home: {e:?}",
-            parts.home
+            "Failed to mount {} partition {source} as {fs_type} to {target}: {e:?}",
+            mnt.name
         ))
-    })?;
-    swapon("/dev/mapper/cswap", 0)
-        .map_err(|e| Error::Mount(format!("Failed to swapon {}: {e:?}", parts.swap)))?;
-    Ok(())
+    })
 }
 
 pub fn run_mdev() -> Result<()> {
@@ -118,65 +450,63 @@ pub fn run_mdev() -> Result<()> {
     Ok(())
 }
 
+/// A LUKS volume found on a device.
 #[cfg_attr(test, derive(Debug))]
-pub struct Partitions {
-    pub root: String,
-    pub swap: String,
-    pub home: String,
+pub struct Partition {
+    /// The [`cfg::CryptVolume`] name, which is also the mapper name
+    pub name: String,
+    pub uuid: String,
+    pub device: String,
+}
+
+pub fn get_partitions(cfg: &Cfg) -> Result<Vec<Partition>> {
+    let blkid = run_blkid()?;
+    cfg.crypt
+        .iter()
+        .map(|volume| find_partition(&blkid, volume))
+        .collect()
 }
 
-pub fn get_partitions(cfg: &Cfg) -> Result<Partitions> {
+fn run_blkid() -> Result<String> {
     let mut cmd = Command::new("/bin/busybox\0")
         .map_err(|e| Error::Spawn(format!("Failed to instantiate busybox command {e}")))?;
     cmd.arg("blkid\0")
         .map_err(|e| Error::Spawn(format!("Failed to append blkid to busybox command {e}")))?;
-    let tgt = spawn_await_stdout(cmd, 4096)?;
-    let mut root = None;
-    let mut swap = None;
-    let mut home = None;
-    for line in tgt.lines() {
-        // Dirty just checking contains, which essentially mean we also accept part-uuids since they
-        // are on the same line.
-
-        // /dev/nvme1n1p4: ...UUID=... etc
-        if line.contains(&cfg.root_uuid) {
-            let (part, _discard_rest) = line.split_once(':')
-                .ok_or_else(|| Error::FindPartitions(format!("Failed to find root partition device name on blkid line that contains the specified uuid={}, line={line}", cfg.root_uuid)))?;
-            root = Some(part.to_string())
-        } else if line.contains(&cfg.swap_uuid) {
-            let (part, _discard_rest) = line.split_once(':')
-                .ok_or_else(|| Error::FindPartitions(format!("Failed to find swap partition device name on blkid line that contains the specified uuid={}, line={line}", cfg.swap_uuid)))?;
-            swap = Some(part.to_string())
-        } else if line.contains(&cfg.home_uuid) {
-            let (part, _discard_rest) = line.split_once(':')
-                .ok_or_else(|| Error::FindPartitions(format!("Failed to find home partition device name on blkid line that contains the specified uuid={}, line={line}", cfg.home_uuid)))?;
-            home = Some(part.to_string())
-        }
-    }
-    Ok(Partitions {
-        root: root.ok_or_else(|| {
-            Error::FindPartitions(format!(
-                "Failed to find root partition={} from blkid",
-                cfg.root_uuid
-            ))
-        })?,
-        swap: swap.ok_or_else(|| {
-            Error::FindPartitions(format!(
-                "Failed to find swap partition={} from blkid",
-                cfg.swap_uuid
-            ))
-        })?,
-        home: home.ok_or_else(|| {
+    spawn_await_stdout(cmd, 4096)
+}
+
+fn find_partition(blkid: &str, volume: &CryptVolume) -> Result<Partition> {
+    // Dirty just checking contains, which essentially mean we also accept part-uuids since they
+    // are on the same line.
+
+    // /dev/nvme1n1p4: ...UUID=... etc
+    let line = blkid
+        .lines()
+        .find(|line| line.contains(&volume.uuid))
+        .ok_or_else(|| {
             Error::FindPartitions(format!(
-                "Failed to find home partition={} from blkid",
-                cfg.home_uuid
+                "Failed to find {} partition={} from blkid",
+                volume.name, volume.uuid
             ))
-        })?,
+        })?;
+    let (part, _discard_rest) = line.split_once(':')
+        .ok_or_else(|| Error::FindPartitions(format!("Failed to find {} partition device name on blkid line that contains the specified uuid={}, line={line}", volume.name, volume.uuid)))?;
+    Ok(Partition {
+        name: volume.name.clone(),
+        uuid: volume.uuid.clone(),
+        device: part.to_string(),
     })
 }
 
-pub(crate) fn open_cryptodisk(device_name: &str, target_name: &str, pass: &[u8]) -> Result<()> {
-    let key_file = "/crypto_keyfile.txt";
+pub(crate) fn spawn_cryptsetup(
+    device_name: &str,
+    target_name: &str,
+    pass: &[u8],
+    options: &CryptOptions,
+) -> Result<Child> {
+    // Volumes can have different keys
+    let key_file = format!("/crypto_keyfile_{target_name}.txt");
+    let key_file = key_file.as_str();
     match tiny_std::fs::metadata(key_file) {
         Ok(_) => {}
         Err(e) => {
@@ -195,13 +525,19 @@ pub(crate) fn open_cryptodisk(device_name: &str, target_name: &str, pass: &[u8])
             }
         }
     }
-    let mut child = tiny_std::process::Command::new("/sbin/cryptsetup")
-        .map_err(|e| {
+    let mut cmd = tiny_std::process::Command::new("/sbin/cryptsetup").map_err(|e| {
+        Error::Crypt(format!(
+            "Failed to instantiate command /sbin/cryptsetup {e}"
+        ))
+    })?;
+    for arg in options.cryptsetup_args() {
+        cmd.arg(arg.as_str()).map_err(|e| {
             Error::Crypt(format!(
-                "Failed to instantiate command /sbin/cryptsetup {e}"
+                "Failed to instantiate command /sbin/cryptsetup adding arg {arg}: {e}"
             ))
-        })?
-        .arg("--key-file")
+        })?;
+    }
+    cmd.arg("--key-file")
         .map_err(|e| {
             Error::Crypt(format!(
                 "Failed to instantiate command /sbin/cryptsetup adding arg --key-file {e}"
@@ -232,18 +568,7 @@ pub(crate) fn open_cryptodisk(device_name: &str, target_name: &str, pass: &[u8])
             ))
         })?
         .spawn()
-        .map_err(|e| Error::Crypt(format!("Failed to spawn /sbin/cryptsetup {e}")))?;
-    let res = child.wait().map_err(|e| {
-        Error::Crypt(format!(
-            "Failed to await for child process /sbin/cryptsetup: {e}"
-        ))
-    })?;
-    if res != 0 {
-        return Err(Error::Crypt(format!(
-            "Got error from /sbin/cryptsetup, code {res}"
-        )));
-    }
-    Ok(())
+        .map_err(|e| Error::Crypt(format!("Failed to spawn /sbin/cryptsetup {e}")))
 }
 
 pub(crate) fn spawn_await_stdout(mut cmd: Command, buf_size: usize) -> Result<String> {
@@ -283,6 +608,44 @@ pub fn try_unmount() -> Result<()> {
     Ok(())
 }
 
+/// Moves `/dev`, `/proc` and `/sys` into the new root and mounts a tmpfs on its `/run`, so that
+/// device nodes created here survive and the real init finds them mounted. What can't be moved,
+/// because the new root has no directory for it or the move fails, is unmounted instead.
+pub fn move_pseudo_filesystems() -> Result<()> {
+    for (source, target, name) in [
+        ("/dev\0", "/mnt/root/dev\0", "devtmpfs"),
+        ("/proc\0", "/mnt/root/proc\0", "proc fs"),
+        ("/sys\0", "/mnt/root/sys\0", "sysfs"),
+    ] {
+        if !is_dir(target) {
+            unix_eprintln!("No directory for {name} in the new root, unmounting it");
+        } else if let Err(e) = sys::mount(source, target, "\0", mount_opts::MS_MOVE, None) {
+            unix_eprintln!("Failed to move {name} into the new root, unmounting it: {e:?}");
+        } else {
+            continue;
+        }
+        if let Err(e) = unmount(source) {
+            unix_eprintln!("Failed to unmount {name} {e}");
+        }
+    }
+    if !is_dir("/mnt/root/run\0") {
+        unix_eprintln!("No /run in the new root to mount a tmpfs on");
+    } else if let Err(e) = sys::mount(
+        "tmpfs\0",
+        "/mnt/root/run\0",
+        "tmpfs\0",
+        mount_opts::MS_NOSUID | mount_opts::MS_NODEV,
+        Some("mode=0755\0"),
+    ) {
+        unix_eprintln!("Failed to mount tmpfs at /mnt/root/run: {e:?}");
+    }
+    Ok(())
+}
+
+fn is_dir(path: &str) -> bool {
+    tiny_std::fs::metadata(path).is_ok_and(|meta| meta.is_dir())
+}
+
 pub fn switch_root() -> Error {
     let mut cmd = match Command::new("/bin/busybox\0") {
         Ok(cmd) => cmd,
@@ -330,60 +693,75 @@ pub fn bail_to_shell() -> Error {
     ))
 }
 
-#[derive(Debug)]
-pub struct Cfg {
-    root_uuid: String,
-    swap_uuid: String,
-    home_uuid: String,
-    key_file_path: String,
-}
-
-pub fn read_cfg(cfg_path: &str) -> Result<Cfg> {
-    let content = tiny_std::fs::read_to_string(cfg_path)
-        .map_err(|e| Error::Cfg(format!("Failed to read cfg at {cfg_path}: {e}")))?;
-    let mut root_uuid = None;
-    let mut swap_uuid = None;
-    let mut home_uuid = None;
-    let mut key_file_path = None;
-    for (ind, line) in content.lines().enumerate() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
-        // Allow comments
-        if trimmed.starts_with("//") {
-            continue;
-        }
-        let (key, value) = trimmed.split_once('=')
-            .ok_or_else(|| Error::Cfg(format!("Found non empty line that doesn't contain '=' or starts with '//' [{ind}]: '{line}'")))?;
-        match key {
-            "root" => root_uuid = Some(value.to_string()),
-            "home" => home_uuid = Some(value.to_string()),
-            "swap" => swap_uuid = Some(value.to_string()),
-            "key_file_path" => key_file_path = Some(value.to_string()),
-            other => {
-                return Err(Error::Cfg(format!(
-                    "Unrecognized key in config file {other} at [{ind}]: '{line}'"
-                )))
-            }
-        }
-    }
-    Ok(Cfg {
-        root_uuid: root_uuid
-            .ok_or_else(|| Error::Cfg(format!("No root uuid found in cfg at path {cfg_path}")))?,
-        swap_uuid: swap_uuid
-            .ok_or_else(|| Error::Cfg(format!("No swap uuid found in cfg at path {cfg_path}")))?,
-        home_uuid: home_uuid
-            .ok_or_else(|| Error::Cfg(format!("No home uuid found in cfg at path {cfg_path}")))?,
-        key_file_path: key_file_path.ok_or_else(|| {
-            Error::Cfg(format!("No key_file_path found in cfg at path {cfg_path}"))
-        })?,
-    })
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cfg::Swap;
+    use alloc::string::ToString;
+
+    #[test]
+    fn skipped_optional_steps() {
+        let crypt = |name: &str, options: &[&str]| CryptVolume {
+            name: name.to_string(),
+            uuid: String::new(),
+            key_file: None,
+            key_mount: None,
+            options: options.iter().map(ToString::to_string).collect(),
+            unlock: None,
+            header_sha256: None,
+        };
+        let cfg = Cfg {
+            key_file_path: None,
+            unlock: Unlock::Cryptsetup,
+            pseudo_fs: PseudoFs::Unmount,
+            md: Vec::new(),
+            crypt: vec![crypt("croot", &[]), crypt("cswap", &["nofail"])],
+            mounts: vec![Mount {
+                name: "root".to_string(),
+                source: "croot".to_string(),
+                target: "/".to_string(),
+                fs_type: None,
+                options: Vec::new(),
+                subvol: None,
+            }],
+            swaps: vec![Swap {
+                name: "cswap".to_string(),
+                source: "cswap".to_string(),
+                options: vec!["nofail".to_string()],
+                mount: None,
+                resume_offset: None,
+            }],
+            zram: Vec::new(),
+        };
+        let planned = plan::plan(&cfg).unwrap();
+        let find = |name: &str| {
+            planned
+                .iter()
+                .position(|p| p.step.describe() == name)
+                .unwrap()
+        };
+        let skipped = |name: &str| [(planned[find(name)].step, Error::Mount(String::new()))];
+        // The swap comes first but root doesn't need it
+        assert!(find("swap.cswap") < find("mount.root"));
+        let root = &planned[find("mount.root")];
+        assert!(check_dependencies(root, &skipped("swap.cswap")).is_ok());
+        assert!(check_dependencies(root, &skipped("crypt.cswap")).is_ok());
+        let swap = &planned[find("swap.cswap")];
+        assert!(check_dependencies(swap, &skipped("crypt.cswap")).is_err());
+        // A skipped physical volume doesn't stop its volume group
+        let mut cfg = cfg.clone();
+        cfg.mounts[0].source = "vg0/root".to_string();
+        cfg.crypt[0].options = vec!["nofail".to_string()];
+        let planned = plan::plan(&cfg).unwrap();
+        let activate = planned
+            .iter()
+            .find(|p| matches!(p.step, Step::Activate(_)))
+            .unwrap();
+        let croot = [(Step::Unlock(&cfg.crypt[0]), Error::Mount(String::new()))];
+        assert!(check_dependencies(activate, &croot).is_ok());
+        // Only its possible physical volumes explain a failing activation, not the swap's volume
+        assert_eq!(vec![Step::Unlock(&cfg.crypt[0])], activate.waits_for);
+    }
 
     // Needs your testing machine's disk uuids
     #[test]
@@ -0,0 +1,804 @@
+//! LUKS1 and LUKS2 on-disk headers, see the LUKS1 On-Disk Format Specification and the LUKS2
+//! On-Disk Format Specification. Of the two LUKS2 header copies the one with a valid checksum and
+//! the higher seqid is used.
+use crate::error::{Error, Result};
+use crate::json::{self, Value};
+use crate::sys;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Write};
+use sha2::Digest;
+use tiny_std::fs::File;
+use tiny_std::unix::fd::AsRawFd;
+
+const MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+/// Of the secondary LUKS2 header
+const SECONDARY_MAGIC: &[u8; 6] = b"SKUL\xba\xbe";
+const LUKS1_HEADER_SIZE: usize = 592;
+const LUKS1_KEYSLOTS: usize = 8;
+const LUKS1_KEYSLOT_ACTIVE: u32 = 0x00AC_71F3;
+const LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+/// The largest metadata area the spec allows
+const LUKS2_MAX_HEADER_SIZE: u64 = 4 * 1024 * 1024;
+/// Where the secondary header can be, one of the sizes the spec allows for the primary one
+const LUKS2_SECONDARY_OFFSETS: &[u64] = &[
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+/// The checksum in the binary header, the rest of the field is zeroed
+const LUKS2_CSUM: core::ops::Range<usize> = 448..512;
+
+#[derive(Debug, Clone)]
+pub enum LuksHeader {
+    V1(Luks1Header),
+    V2(Luks2Header),
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks1Header {
+    pub cipher_name: String,
+    pub cipher_mode: String,
+    pub hash_spec: String,
+    /// In 512 byte sectors
+    pub payload_offset: u32,
+    pub key_bytes: u32,
+    pub mk_digest: [u8; 20],
+    pub mk_digest_salt: [u8; 32],
+    pub mk_digest_iterations: u32,
+    pub uuid: String,
+    pub keyslots: Vec<Luks1Keyslot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks1Keyslot {
+    pub id: u32,
+    pub active: bool,
+    pub iterations: u32,
+    pub salt: [u8; 32],
+    /// In 512 byte sectors
+    pub key_material_offset: u32,
+    pub stripes: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Header {
+    /// Size of the binary header and JSON area
+    pub hdr_size: u64,
+    pub seqid: u64,
+    pub label: String,
+    pub checksum_alg: String,
+    pub uuid: String,
+    pub subsystem: String,
+    pub keyslots: Vec<Luks2Keyslot>,
+    pub tokens: Vec<Luks2Token>,
+    pub segments: Vec<Luks2Segment>,
+    pub digests: Vec<Luks2Digest>,
+    pub json_size: u64,
+    pub keyslots_size: u64,
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Keyslot {
+    pub id: u32,
+    /// `luks2`, or `reencrypt` during reencryption
+    pub kind: String,
+    pub key_size: u32,
+    pub priority: Option<u32>,
+    pub area: Luks2Area,
+    pub af: Luks2Af,
+    pub kdf: Kdf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Area {
+    pub kind: String,
+    /// In bytes
+    pub offset: u64,
+    /// In bytes
+    pub size: u64,
+    pub encryption: String,
+    pub key_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Af {
+    pub kind: String,
+    pub stripes: u32,
+    pub hash: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Kdf {
+    Pbkdf2 {
+        hash: String,
+        iterations: u32,
+        salt: Vec<u8>,
+    },
+    Argon2 {
+        /// `argon2i` or `argon2id`
+        kind: String,
+        time: u32,
+        /// In KiB
+        memory: u32,
+        cpus: u32,
+        salt: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Token {
+    pub id: u32,
+    /// `systemd-fido2`, `systemd-tpm2` etc, anything goes
+    pub kind: String,
+    pub keyslots: Vec<u32>,
+    /// Type specific fields are left as is
+    pub json: Value,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Segment {
+    pub id: u32,
+    pub kind: String,
+    /// In bytes
+    pub offset: u64,
+    /// In bytes, `None` for `dynamic`, meaning until the end of the device
+    pub size: Option<u64>,
+    pub iv_tweak: u64,
+    pub encryption: String,
+    pub sector_size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Luks2Digest {
+    pub id: u32,
+    pub kind: String,
+    pub keyslots: Vec<u32>,
+    pub segments: Vec<u32>,
+    pub hash: String,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub digest: Vec<u8>,
+}
+
+impl LuksHeader {
+    pub fn version(&self) -> u16 {
+        match self {
+            LuksHeader::V1(_) => 1,
+            LuksHeader::V2(_) => 2,
+        }
+    }
+
+    pub fn uuid(&self) -> &str {
+        match self {
+            LuksHeader::V1(h) => &h.uuid,
+            LuksHeader::V2(h) => &h.uuid,
+        }
+    }
+}
+
+impl Luks2Header {
+    /// Keyslots that a token of `kind` can unlock.
+    pub fn keyslots_for_token(&self, kind: &str) -> Vec<u32> {
+        let mut keyslots = Vec::new();
+        for token in self.tokens.iter().filter(|t| t.kind == kind) {
+            for slot in &token.keyslots {
+                if !keyslots.contains(slot) {
+                    keyslots.push(*slot);
+                }
+            }
+        }
+        keyslots
+    }
+}
+
+/// Checks that the detached header at `path` hashes to `sha256`, so that a stale copy in the image
+/// is caught before it's used to unlock anything.
+pub fn check_header_checksum(path: &str, sha256: &str) -> Result<()> {
+    let content = tiny_std::fs::read(path)
+        .map_err(|e| Error::Luks(format!("Failed to read detached header {path}: {e}")))?;
+    let actual = hex(&sha2::Sha256::digest(&content));
+    if !actual.eq_ignore_ascii_case(sha256) {
+        return Err(Error::Luks(format!(
+            "Detached header {path} has sha256 {actual}, expected {sha256}, is it outdated?"
+        )));
+    }
+    Ok(())
+}
+
+/// Reads the header at the start of `device`, or a detached header file.
+pub fn read_header(device: &str) -> Result<LuksHeader> {
+    let file =
+        File::open(device).map_err(|e| Error::Luks(format!("Failed to open {device}: {e}")))?;
+    let mut buf = vec![0u8; LUKS1_HEADER_SIZE];
+    read_at(&file, device, &mut buf, 0)?;
+    if &buf[..6] != MAGIC {
+        return Err(Error::Luks(format!("{device} has no LUKS header")));
+    }
+    match u16::from_be_bytes([buf[6], buf[7]]) {
+        1 => parse_luks1(&buf).map(LuksHeader::V1),
+        2 => read_luks2(device, &|buf, offset| read_at(&file, device, buf, offset))
+            .map(LuksHeader::V2),
+        v => Err(Error::Luks(format!(
+            "{device} has unsupported LUKS version {v}"
+        ))),
+    }
+}
+
+/// The valid copy with the higher seqid, the secondary one is found through the primary one's
+/// size, or by trying every allowed offset if the primary one is broken.
+fn read_luks2(device: &str, read_at: &dyn Fn(&mut [u8], u64) -> Result<()>) -> Result<Luks2Header> {
+    let primary = read_luks2_copy(device, read_at, 0);
+    let offsets = match &primary {
+        Ok(header) => vec![header.hdr_size],
+        Err(_) => LUKS2_SECONDARY_OFFSETS.to_vec(),
+    };
+    let secondary = offsets
+        .into_iter()
+        .find_map(|offset| read_luks2_copy(device, read_at, offset).ok());
+    match (primary, secondary) {
+        (Ok(primary), Some(secondary)) if secondary.seqid > primary.seqid => Ok(secondary),
+        (Ok(primary), _) => Ok(primary),
+        (Err(_), Some(secondary)) => Ok(secondary),
+        (Err(e), None) => Err(Error::Luks(format!(
+            "{device} has no valid LUKS2 header, the primary one: {e:?}"
+        ))),
+    }
+}
+
+fn read_luks2_copy(
+    device: &str,
+    read_at: &dyn Fn(&mut [u8], u64) -> Result<()>,
+    offset: u64,
+) -> Result<Luks2Header> {
+    let mut buf = vec![0u8; LUKS2_BINARY_HEADER_SIZE];
+    read_at(&mut buf, offset)?;
+    let magic = if offset == 0 { MAGIC } else { SECONDARY_MAGIC };
+    if &buf[..6] != magic || u16::from_be_bytes([buf[6], buf[7]]) != 2 {
+        return Err(Error::Luks(format!(
+            "{device} has no LUKS2 header at {offset}"
+        )));
+    }
+    let hdr_size = be_u64(&buf, 8);
+    if !(LUKS2_BINARY_HEADER_SIZE as u64 + 1..=LUKS2_MAX_HEADER_SIZE).contains(&hdr_size) {
+        return Err(Error::Luks(format!(
+            "{device} has a LUKS2 header size of {hdr_size} at {offset}, which is out of spec"
+        )));
+    }
+    if be_u64(&buf, 256) != offset {
+        return Err(Error::Luks(format!(
+            "{device} has a LUKS2 header at {offset} that says it's at {}",
+            be_u64(&buf, 256)
+        )));
+    }
+    buf.resize(hdr_size as usize, 0);
+    read_at(
+        &mut buf[LUKS2_BINARY_HEADER_SIZE..],
+        offset + LUKS2_BINARY_HEADER_SIZE as u64,
+    )?;
+    check_luks2_checksum(&buf)
+        .map_err(|e| Error::Luks(format!("{device} LUKS2 header at {offset}: {e:?}")))?;
+    parse_luks2(&buf)
+}
+
+/// The checksum covers the binary header, with the checksum zeroed, and the JSON area.
+fn check_luks2_checksum(buf: &[u8]) -> Result<()> {
+    let alg = c_str(&buf[72..104])?;
+    let mut zeroed = buf.to_vec();
+    zeroed[LUKS2_CSUM].fill(0);
+    let actual = match alg.as_str() {
+        "sha1" => sha1::Sha1::digest(&zeroed).to_vec(),
+        "sha256" => sha2::Sha256::digest(&zeroed).to_vec(),
+        "sha512" => sha2::Sha512::digest(&zeroed).to_vec(),
+        other => {
+            return Err(Error::Luks(format!(
+                "Unsupported LUKS2 checksum algorithm {other}"
+            )))
+        }
+    };
+    let stored = &buf[LUKS2_CSUM];
+    if stored[..actual.len()] != actual[..] || stored[actual.len()..].iter().any(|b| *b != 0) {
+        return Err(Error::Luks(format!(
+            "LUKS2 header checksum mismatch, {alg} is {}, expected {}",
+            hex(&actual),
+            hex(&stored[..actual.len()])
+        )));
+    }
+    Ok(())
+}
+
+fn read_at(file: &File, device: &str, buf: &mut [u8], offset: u64) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = sys::pread(file.as_raw_fd(), &mut buf[read..], offset + read as u64)
+            .map_err(|e| Error::Luks(format!("Failed to read header of {device}: {e:?}")))?;
+        if n == 0 {
+            return Err(Error::Luks(format!("{device} is too short for its header")));
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+pub fn parse_luks1(buf: &[u8]) -> Result<Luks1Header> {
+    if buf.len() < LUKS1_HEADER_SIZE || &buf[..6] != MAGIC {
+        return Err(Error::Luks("Not a LUKS1 header".into()));
+    }
+    let mut keyslots = Vec::with_capacity(LUKS1_KEYSLOTS);
+    for id in 0..LUKS1_KEYSLOTS {
+        let slot = &buf[208 + id * 48..208 + (id + 1) * 48];
+        keyslots.push(Luks1Keyslot {
+            id: id as u32,
+            active: be_u32(slot, 0) == LUKS1_KEYSLOT_ACTIVE,
+            iterations: be_u32(slot, 4),
+            salt: slot[8..40].try_into().unwrap(),
+            key_material_offset: be_u32(slot, 40),
+            stripes: be_u32(slot, 44),
+        });
+    }
+    Ok(Luks1Header {
+        cipher_name: c_str(&buf[8..40])?,
+        cipher_mode: c_str(&buf[40..72])?,
+        hash_spec: c_str(&buf[72..104])?,
+        payload_offset: be_u32(buf, 104),
+        key_bytes: be_u32(buf, 108),
+        mk_digest: buf[112..132].try_into().unwrap(),
+        mk_digest_salt: buf[132..164].try_into().unwrap(),
+        mk_digest_iterations: be_u32(buf, 164),
+        uuid: c_str(&buf[168..208])?,
+        keyslots,
+    })
+}
+
+/// `buf` is the binary header followed by the JSON area.
+pub fn parse_luks2(buf: &[u8]) -> Result<Luks2Header> {
+    if buf.len() <= LUKS2_BINARY_HEADER_SIZE || (&buf[..6] != MAGIC && &buf[..6] != SECONDARY_MAGIC)
+    {
+        return Err(Error::Luks("Not a LUKS2 header".into()));
+    }
+    let json_area = &buf[LUKS2_BINARY_HEADER_SIZE..];
+    let json_len = json_area
+        .iter()
+        .position(|b| *b == 0)
+        .unwrap_or(json_area.len());
+    let json = core::str::from_utf8(&json_area[..json_len])
+        .map_err(|e| Error::Luks(format!("LUKS2 metadata not utf8: {e}")))?;
+    let meta = json::parse(json)?;
+    let config = field(&meta, "config")?;
+    Ok(Luks2Header {
+        hdr_size: be_u64(buf, 8),
+        seqid: be_u64(buf, 16),
+        label: c_str(&buf[24..72])?,
+        checksum_alg: c_str(&buf[72..104])?,
+        uuid: c_str(&buf[168..208])?,
+        subsystem: c_str(&buf[208..256])?,
+        keyslots: objects(&meta, "keyslots", parse_keyslot)?,
+        tokens: objects(&meta, "tokens", parse_token)?,
+        segments: objects(&meta, "segments", parse_segment)?,
+        digests: objects(&meta, "digests", parse_digest)?,
+        json_size: u64_field(config, "json_size")?,
+        keyslots_size: u64_field(config, "keyslots_size")?,
+        flags: match config.get("flags").and_then(Value::as_array) {
+            Some(flags) => flags
+                .iter()
+                .map(|f| f.as_str().map(String::from))
+                .collect::<Option<_>>()
+                .ok_or_else(|| Error::Luks("LUKS2 config flags not strings".into()))?,
+            None => Vec::new(),
+        },
+    })
+}
+
+fn parse_keyslot(id: u32, v: &Value) -> Result<Luks2Keyslot> {
+    let area = field(v, "area")?;
+    let af = field(v, "af")?;
+    let kdf = field(v, "kdf")?;
+    let kdf_kind = str_field(kdf, "type")?;
+    let kdf = match kdf_kind.as_str() {
+        "pbkdf2" => Kdf::Pbkdf2 {
+            hash: str_field(kdf, "hash")?,
+            iterations: u32_field(kdf, "iterations")?,
+            salt: base64_field(kdf, "salt")?,
+        },
+        "argon2i" | "argon2id" => Kdf::Argon2 {
+            time: u32_field(kdf, "time")?,
+            memory: u32_field(kdf, "memory")?,
+            cpus: u32_field(kdf, "cpus")?,
+            salt: base64_field(kdf, "salt")?,
+            kind: kdf_kind,
+        },
+        other => return Err(Error::Luks(format!("Keyslot {id} has unknown kdf {other}"))),
+    };
+    Ok(Luks2Keyslot {
+        id,
+        kind: str_field(v, "type")?,
+        key_size: u32_field(v, "key_size")?,
+        priority: v
+            .get("priority")
+            .map(|p| {
+                p.as_u64()
+                    .map(|p| p as u32)
+                    .ok_or_else(|| Error::Luks(format!("Keyslot {id} has a malformed priority")))
+            })
+            .transpose()?,
+        area: Luks2Area {
+            kind: str_field(area, "type")?,
+            offset: u64_field(area, "offset")?,
+            size: u64_field(area, "size")?,
+            encryption: str_field(area, "encryption")?,
+            key_size: u32_field(area, "key_size")?,
+        },
+        af: Luks2Af {
+            kind: str_field(af, "type")?,
+            stripes: u32_field(af, "stripes")?,
+            hash: str_field(af, "hash")?,
+        },
+        kdf,
+    })
+}
+
+fn parse_token(id: u32, v: &Value) -> Result<Luks2Token> {
+    Ok(Luks2Token {
+        id,
+        kind: str_field(v, "type")?,
+        keyslots: id_list(v, "keyslots")?,
+        json: v.clone(),
+    })
+}
+
+fn parse_segment(id: u32, v: &Value) -> Result<Luks2Segment> {
+    let size = field(v, "size")?;
+    Ok(Luks2Segment {
+        id,
+        kind: str_field(v, "type")?,
+        offset: u64_field(v, "offset")?,
+        size: if size.as_str() == Some("dynamic") {
+            None
+        } else {
+            Some(u64_field(v, "size")?)
+        },
+        iv_tweak: u64_field(v, "iv_tweak")?,
+        encryption: str_field(v, "encryption")?,
+        sector_size: u32_field(v, "sector_size")?,
+    })
+}
+
+fn parse_digest(id: u32, v: &Value) -> Result<Luks2Digest> {
+    Ok(Luks2Digest {
+        id,
+        kind: str_field(v, "type")?,
+        keyslots: id_list(v, "keyslots")?,
+        segments: id_list(v, "segments")?,
+        hash: str_field(v, "hash")?,
+        iterations: u32_field(v, "iterations")?,
+        salt: base64_field(v, "salt")?,
+        digest: base64_field(v, "digest")?,
+    })
+}
+
+/// LUKS2 sections are objects keyed by stringified ids.
+fn objects<T>(meta: &Value, key: &str, parse: fn(u32, &Value) -> Result<T>) -> Result<Vec<T>> {
+    let entries = field(meta, key)?
+        .as_object()
+        .ok_or_else(|| Error::Luks(format!("LUKS2 {key} is not an object")))?;
+    let mut parsed = Vec::with_capacity(entries.len());
+    for (id, value) in entries {
+        let id = id
+            .parse()
+            .map_err(|_| Error::Luks(format!("LUKS2 {key} has a non numeric id {id}")))?;
+        parsed.push(parse(id, value)?);
+    }
+    Ok(parsed)
+}
+
+fn field<'a>(v: &'a Value, key: &str) -> Result<&'a Value> {
+    v.get(key)
+        .ok_or_else(|| Error::Luks(format!("LUKS2 metadata missing {key}")))
+}
+
+fn str_field(v: &Value, key: &str) -> Result<String> {
+    field(v, key)?
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| Error::Luks(format!("LUKS2 {key} is not a string")))
+}
+
+fn u64_field(v: &Value, key: &str) -> Result<u64> {
+    field(v, key)?
+        .as_u64()
+        .ok_or_else(|| Error::Luks(format!("LUKS2 {key} is not an unsigned number")))
+}
+
+fn u32_field(v: &Value, key: &str) -> Result<u32> {
+    u64_field(v, key)?
+        .try_into()
+        .map_err(|_| Error::Luks(format!("LUKS2 {key} doesn't fit in 32 bits")))
+}
+
+fn base64_field(v: &Value, key: &str) -> Result<Vec<u8>> {
+    base64_decode(&str_field(v, key)?)
+        .ok_or_else(|| Error::Luks(format!("LUKS2 {key} is not base64")))
+}
+
+fn id_list(v: &Value, key: &str) -> Result<Vec<u32>> {
+    field(v, key)?
+        .as_array()
+        .and_then(|ids| {
+            ids.iter()
+                .map(|id| id.as_u64().and_then(|id| u32::try_from(id).ok()))
+                .collect()
+        })
+        .ok_or_else(|| Error::Luks(format!("LUKS2 {key} is not a list of ids")))
+}
+
+fn c_str(bytes: &[u8]) -> Result<String> {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len])
+        .map(String::from)
+        .map_err(|e| Error::Luks(format!("LUKS header string not utf8: {e}")))
+}
+
+fn be_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Standard alphabet with padding, as cryptsetup writes it.
+pub fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let sextet = |c: u8| -> Option<u32> {
+        Some(match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        } as u32)
+    };
+    let trimmed = input.trim_end_matches('=');
+    if !input.len().is_multiple_of(4) || input.len() - trimmed.len() > 2 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(trimmed.len() * 3 / 4);
+    for chunk in trimmed.as_bytes().chunks(4) {
+        let mut acc = 0u32;
+        for (ind, c) in chunk.iter().enumerate() {
+            acc |= sextet(*c)? << (18 - 6 * ind);
+        }
+        let bytes = acc.to_be_bytes();
+        out.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+/// Roughly `cryptsetup luksDump`.
+impl Display for LuksHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LuksHeader::V1(h) => {
+                writeln!(f, "LUKS header information")?;
+                writeln!(f, "Version:        1")?;
+                writeln!(f, "Cipher name:    {}", h.cipher_name)?;
+                writeln!(f, "Cipher mode:    {}", h.cipher_mode)?;
+                writeln!(f, "Hash spec:      {}", h.hash_spec)?;
+                writeln!(f, "Payload offset: {}", h.payload_offset)?;
+                writeln!(f, "MK bits:        {}", h.key_bytes * 8)?;
+                writeln!(f, "MK digest:      {}", hex(&h.mk_digest))?;
+                writeln!(f, "MK salt:        {}", hex(&h.mk_digest_salt))?;
+                writeln!(f, "MK iterations:  {}", h.mk_digest_iterations)?;
+                writeln!(f, "UUID:           {}", h.uuid)?;
+                for slot in &h.keyslots {
+                    if slot.active {
+                        writeln!(f, "Key Slot {}: ENABLED", slot.id)?;
+                        writeln!(f, "\tIterations:         \t{}", slot.iterations)?;
+                        writeln!(f, "\tSalt:               \t{}", hex(&slot.salt))?;
+                        writeln!(f, "\tKey material offset:\t{}", slot.key_material_offset)?;
+                        writeln!(f, "\tAF stripes:         \t{}", slot.stripes)?;
+                    } else {
+                        writeln!(f, "Key Slot {}: DISABLED", slot.id)?;
+                    }
+                }
+            }
+            LuksHeader::V2(h) => {
+                writeln!(f, "LUKS header information")?;
+                writeln!(f, "Version:       \t2")?;
+                writeln!(f, "Epoch:         \t{}", h.seqid)?;
+                writeln!(f, "Metadata area: \t{} [bytes]", h.hdr_size)?;
+                writeln!(f, "Keyslots area: \t{} [bytes]", h.keyslots_size)?;
+                writeln!(f, "UUID:          \t{}", h.uuid)?;
+                writeln!(f, "Label:         \t{}", h.label)?;
+                writeln!(f, "Subsystem:     \t{}", h.subsystem)?;
+                writeln!(f, "Flags:         \t{}", h.flags.join(" "))?;
+                writeln!(f, "\nData segments:")?;
+                for seg in &h.segments {
+                    writeln!(f, "  {}: {}", seg.id, seg.kind)?;
+                    writeln!(f, "\toffset: {} [bytes]", seg.offset)?;
+                    match seg.size {
+                        Some(size) => writeln!(f, "\tlength: {size} [bytes]")?,
+                        None => writeln!(f, "\tlength: (whole device)")?,
+                    }
+                    writeln!(f, "\tcipher: {}", seg.encryption)?;
+                    writeln!(f, "\tsector: {} [bytes]", seg.sector_size)?;
+                }
+                writeln!(f, "\nKeyslots:")?;
+                for slot in &h.keyslots {
+                    writeln!(f, "  {}: {}", slot.id, slot.kind)?;
+                    writeln!(f, "\tKey:        {} bits", slot.key_size * 8)?;
+                    if let Some(priority) = slot.priority {
+                        writeln!(f, "\tPriority:   {priority}")?;
+                    }
+                    writeln!(f, "\tCipher:     {}", slot.area.encryption)?;
+                    match &slot.kdf {
+                        Kdf::Pbkdf2 {
+                            hash,
+                            iterations,
+                            salt,
+                        } => {
+                            writeln!(f, "\tPBKDF:      pbkdf2")?;
+                            writeln!(f, "\tHash:       {hash}")?;
+                            writeln!(f, "\tIterations: {iterations}")?;
+                            writeln!(f, "\tSalt:       {}", hex(salt))?;
+                        }
+                        Kdf::Argon2 {
+                            kind,
+                            time,
+                            memory,
+                            cpus,
+                            salt,
+                        } => {
+                            writeln!(f, "\tPBKDF:      {kind}")?;
+                            writeln!(f, "\tTime cost:  {time}")?;
+                            writeln!(f, "\tMemory:     {memory}")?;
+                            writeln!(f, "\tThreads:    {cpus}")?;
+                            writeln!(f, "\tSalt:       {}", hex(salt))?;
+                        }
+                    }
+                    writeln!(f, "\tAF stripes: {}", slot.af.stripes)?;
+                    writeln!(f, "\tAF hash:    {}", slot.af.hash)?;
+                    writeln!(f, "\tArea offset:{} [bytes]", slot.area.offset)?;
+                    writeln!(f, "\tArea length:{} [bytes]", slot.area.size)?;
+                }
+                writeln!(f, "\nTokens:")?;
+                for token in &h.tokens {
+                    writeln!(f, "  {}: {}", token.id, token.kind)?;
+                    writeln!(f, "\tKeyslots:   {:?}", token.keyslots)?;
+                }
+                writeln!(f, "\nDigests:")?;
+                for digest in &h.digests {
+                    writeln!(f, "  {}: {}", digest.id, digest.kind)?;
+                    writeln!(f, "\tKeyslots:   {:?}", digest.keyslots)?;
+                    writeln!(f, "\tSegments:   {:?}", digest.segments)?;
+                    writeln!(f, "\tHash:       {}", digest.hash)?;
+                    writeln!(f, "\tIterations: {}", digest.iterations)?;
+                    writeln!(f, "\tSalt:       {}", hex(&digest.salt))?;
+                    writeln!(f, "\tDigest:     {}", hex(&digest.digest))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    const LUKS2_JSON: &str = r#"{"keyslots":{"0":{"type":"luks2","key_size":64,"af":{"type":"luks1","stripes":4000,"hash":"sha256"},"area":{"type":"raw","offset":"32768","size":"258048","encryption":"aes-xts-plain64","key_size":64},"kdf":{"type":"argon2id","time":4,"memory":1048576,"cpus":4,"salt":"c2FsdHNhbHQ="}},"1":{"type":"luks2","key_size":64,"priority":2,"af":{"type":"luks1","stripes":4000,"hash":"sha256"},"area":{"type":"raw","offset":"290816","size":"258048","encryption":"aes-xts-plain64","key_size":64},"kdf":{"type":"pbkdf2","hash":"sha256","iterations":1000,"salt":"AAEC"}}},"tokens":{"0":{"type":"systemd-fido2","keyslots":["1"],"fido2-credential":"abc"}},"segments":{"0":{"type":"crypt","offset":"16777216","size":"dynamic","iv_tweak":"0","encryption":"aes-xts-plain64","sector_size":512}},"digests":{"0":{"type":"pbkdf2","keyslots":["0","1"],"segments":["0"],"hash":"sha256","iterations":1000,"salt":"AAEC","digest":"AAECAw=="}},"config":{"json_size":"12288","keyslots_size":"16744448"}}"#;
+
+    #[test]
+    fn parses_luks2() {
+        let mut buf = vec![0u8; LUKS2_BINARY_HEADER_SIZE + 12288];
+        buf[..6].copy_from_slice(MAGIC);
+        buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+        let hdr_size = buf.len() as u64;
+        buf[8..16].copy_from_slice(&hdr_size.to_be_bytes());
+        buf[16..24].copy_from_slice(&3u64.to_be_bytes());
+        buf[168..168 + 36].copy_from_slice(b"7e127144-f930-4656-84b4-d6a27fa7caa6");
+        buf[LUKS2_BINARY_HEADER_SIZE..LUKS2_BINARY_HEADER_SIZE + LUKS2_JSON.len()]
+            .copy_from_slice(LUKS2_JSON.as_bytes());
+        let header = parse_luks2(&buf).unwrap();
+        assert_eq!("7e127144-f930-4656-84b4-d6a27fa7caa6", header.uuid);
+        assert_eq!(3, header.seqid);
+        assert_eq!(2, header.keyslots.len());
+        assert!(matches!(
+            &header.keyslots[0].kdf,
+            Kdf::Argon2 { memory: 1_048_576, salt, .. } if salt == b"saltsalt"
+        ));
+        assert_eq!(Some(2), header.keyslots[1].priority);
+        assert_eq!(32768, header.keyslots[0].area.offset);
+        assert_eq!(None, header.segments[0].size);
+        assert_eq!(16_777_216, header.segments[0].offset);
+        assert_eq!(vec![0, 1], header.digests[0].keyslots);
+        assert_eq!(vec![0, 1, 2, 3], header.digests[0].digest);
+        assert_eq!(vec![1], header.keyslots_for_token("systemd-fido2"));
+        let dump = LuksHeader::V2(header).to_string();
+        assert!(dump.contains("PBKDF:      argon2id"));
+    }
+
+    /// A primary and a secondary header copy of 16K each, with valid checksums.
+    fn luks2_image(primary_seqid: u64, secondary_seqid: u64) -> Vec<u8> {
+        const HDR_SIZE: usize = 0x4000;
+        let mut image = vec![0u8; 2 * HDR_SIZE];
+        for (ind, (magic, seqid)) in [(MAGIC, primary_seqid), (SECONDARY_MAGIC, secondary_seqid)]
+            .into_iter()
+            .enumerate()
+        {
+            let offset = (ind * HDR_SIZE) as u64;
+            let buf = &mut image[ind * HDR_SIZE..(ind + 1) * HDR_SIZE];
+            buf[..6].copy_from_slice(magic);
+            buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+            buf[8..16].copy_from_slice(&(HDR_SIZE as u64).to_be_bytes());
+            buf[16..24].copy_from_slice(&seqid.to_be_bytes());
+            buf[72..78].copy_from_slice(b"sha256");
+            buf[256..264].copy_from_slice(&offset.to_be_bytes());
+            buf[LUKS2_BINARY_HEADER_SIZE..LUKS2_BINARY_HEADER_SIZE + LUKS2_JSON.len()]
+                .copy_from_slice(LUKS2_JSON.as_bytes());
+            let csum = sha2::Sha256::digest(&buf[..]);
+            buf[LUKS2_CSUM][..csum.len()].copy_from_slice(&csum);
+        }
+        image
+    }
+
+    fn read_image(image: &[u8]) -> Result<Luks2Header> {
+        read_luks2("image", &|buf, offset| {
+            let start = offset as usize;
+            let src = image
+                .get(start..start + buf.len())
+                .ok_or_else(|| Error::Luks("image is too short".into()))?;
+            buf.copy_from_slice(src);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn picks_valid_luks2_copy() {
+        assert_eq!(4, read_image(&luks2_image(3, 4)).unwrap().seqid);
+        assert_eq!(5, read_image(&luks2_image(5, 4)).unwrap().seqid);
+        // A torn primary header, the secondary one is found without its size
+        let mut image = luks2_image(5, 4);
+        image[LUKS2_BINARY_HEADER_SIZE + 10] ^= 1;
+        assert_eq!(4, read_image(&image).unwrap().seqid);
+        // A newer but broken secondary header
+        let mut image = luks2_image(3, 4);
+        image[0x4000 + LUKS2_BINARY_HEADER_SIZE + 10] ^= 1;
+        assert_eq!(3, read_image(&image).unwrap().seqid);
+        image[LUKS2_BINARY_HEADER_SIZE + 10] ^= 1;
+        assert!(read_image(&image).is_err());
+    }
+
+    #[test]
+    fn parses_luks1() {
+        let mut buf = vec![0u8; LUKS1_HEADER_SIZE];
+        buf[..6].copy_from_slice(MAGIC);
+        buf[6..8].copy_from_slice(&1u16.to_be_bytes());
+        buf[8..11].copy_from_slice(b"aes");
+        buf[40..51].copy_from_slice(b"xts-plain64");
+        buf[72..78].copy_from_slice(b"sha256");
+        buf[104..108].copy_from_slice(&4096u32.to_be_bytes());
+        buf[108..112].copy_from_slice(&64u32.to_be_bytes());
+        buf[208..212].copy_from_slice(&LUKS1_KEYSLOT_ACTIVE.to_be_bytes());
+        buf[252..256].copy_from_slice(&4000u32.to_be_bytes());
+        let header = parse_luks1(&buf).unwrap();
+        assert_eq!("xts-plain64", header.cipher_mode);
+        assert_eq!(4096, header.payload_offset);
+        assert!(header.keyslots[0].active);
+        assert_eq!(4000, header.keyslots[0].stripes);
+        assert!(!header.keyslots[1].active);
+        assert_eq!(Some(b"abc".to_vec()), base64_decode("YWJj"));
+        assert_eq!(Some(b"ab".to_vec()), base64_decode("YWI="));
+        assert_eq!(None, base64_decode("YWI"));
+    }
+}
@@ -0,0 +1,109 @@
+//! LVM logical volumes, referenced as `vg/lv` in the cfg and activated with the `lvm` binary
+//! after their physical volumes have been unlocked.
+use crate::error::{Error, Result};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use tiny_std::process::Command;
+
+const LVM: &str = "/sbin/lvm";
+
+/// Splits a `vg/lv` source.
+pub fn parse_lv(source: &str) -> Option<(&str, &str)> {
+    let (vg, lv) = source.split_once('/')?;
+    let valid = |name: &str| {
+        !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-' | b'+'))
+    };
+    (valid(vg) && valid(lv)).then_some((vg, lv))
+}
+
+/// The device-mapper name of a logical volume, `-` in names are doubled, see dmsetup(8).
+pub fn mapper_name(vg: &str, lv: &str) -> String {
+    format!("{}-{}", vg.replace('-', "--"), lv.replace('-', "--"))
+}
+
+/// `vg/lv` from `/dev/<vg>/<lv>` or `/dev/mapper/<vg>-<lv>`.
+pub fn lv_from_device(device: &str) -> Option<String> {
+    let rest = device.strip_prefix("/dev/")?;
+    if let Some(mapper) = rest.strip_prefix("mapper/") {
+        // The first single `-` separates the vg from the lv
+        let bytes = mapper.as_bytes();
+        let mut ind = 0;
+        while ind < bytes.len() {
+            if bytes[ind] == b'-' {
+                if bytes.get(ind + 1) == Some(&b'-') {
+                    ind += 2;
+                    continue;
+                }
+                let vg = mapper[..ind].replace("--", "-");
+                let lv = mapper[ind + 1..].replace("--", "-");
+                let lv = format!("{vg}/{lv}");
+                return parse_lv(&lv).is_some().then_some(lv);
+            }
+            ind += 1;
+        }
+        return None;
+    }
+    let (vg, _) = rest.split_once('/')?;
+    if matches!(
+        vg,
+        "disk" | "md" | "block" | "char" | "bus" | "input" | "pts" | "shm"
+    ) {
+        return None;
+    }
+    parse_lv(rest).map(|_| String::from(rest))
+}
+
+/// Activates all logical volumes in `vgs`, creating their `/dev/mapper` nodes.
+pub fn activate(vgs: &[&str]) -> Result<()> {
+    let mut cmd = Command::new(LVM)
+        .map_err(|e| Error::Lvm(format!("Failed to instantiate command {LVM} {e}")))?;
+    // `--sysinit` makes vgchange usable in early boot, it skips lvmetad and doesn't start polling
+    // daemons. Without udev running lvm creates the device nodes itself
+    let mut args = Vec::with_capacity(vgs.len() + 3);
+    args.extend(["vgchange", "--sysinit", "-ay"]);
+    args.extend(vgs);
+    for arg in args {
+        cmd.arg(arg).map_err(|e| {
+            Error::Lvm(format!(
+                "Failed to instantiate command {LVM} adding arg {arg}: {e}"
+            ))
+        })?;
+    }
+    let res = cmd
+        .spawn()
+        .map_err(|e| Error::Lvm(format!("Failed to spawn {LVM} {e}")))?
+        .wait()
+        .map_err(|e| Error::Lvm(format!("Failed to await for child process {LVM}: {e}")))?;
+    if res != 0 {
+        return Err(Error::Lvm(format!(
+            "Got error from {LVM} vgchange -ay {vgs:?}, code {res}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lv_names() {
+        assert_eq!(Some(("vg0", "root")), parse_lv("vg0/root"));
+        assert_eq!(None, parse_lv("croot"));
+        assert_eq!(None, parse_lv("vg0/"));
+        assert_eq!(None, parse_lv("vg0/root/x"));
+        assert_eq!("my--vg-lv--home", mapper_name("my-vg", "lv-home"));
+        assert_eq!(
+            Some("my-vg/lv-home"),
+            lv_from_device("/dev/mapper/my--vg-lv--home").as_deref()
+        );
+        assert_eq!(Some("vg0/swap"), lv_from_device("/dev/vg0/swap").as_deref());
+        assert_eq!(None, lv_from_device("/dev/mapper/croot"));
+        assert_eq!(None, lv_from_device("/dev/disk/by-uuid"));
+        assert_eq!(None, lv_from_device("/dev/sda1"));
+    }
+}
@@ -0,0 +1,93 @@
+//! Software RAID arrays, assembled with `mdadm` before anything on them is unlocked.
+use crate::cfg::{DegradedPolicy, MdArray};
+use crate::error::{Error, Result};
+use alloc::format;
+use core::time::Duration;
+use tiny_std::process::{Command, Stdio};
+use unix_print::unix_println;
+
+const MDADM: &str = "/sbin/mdadm";
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether `uuid` is 32 hex digits, in either mdadm's `:` separated or blkid's `-` separated form.
+pub fn is_md_uuid(uuid: &str) -> bool {
+    let mut digits = 0;
+    for b in uuid.bytes() {
+        match b {
+            b':' | b'-' => {}
+            b if b.is_ascii_hexdigit() => digits += 1,
+            _ => return false,
+        }
+    }
+    digits == 32
+}
+
+/// Assembles the array at `/dev/md/<name>`, waiting for all of its members to show up. If they
+/// don't within the array's timeout it's either started degraded or an error depending on policy.
+pub fn assemble(array: &MdArray) -> Result<()> {
+    let device = format!("/dev/md/{}", array.name);
+    let uuid = format!("--uuid={}", array.uuid);
+    let timeout = Duration::from_secs(array.timeout_secs);
+    let mut waited = Duration::ZERO;
+    loop {
+        // Late disks need their nodes created before mdadm can find them
+        crate::run_mdev()?;
+        if mdadm(&["--assemble", &device, &uuid])? == 0 {
+            return Ok(());
+        }
+        // A partial assembly leaves an inactive array holding the members it found
+        mdadm(&["--stop", &device])?;
+        if waited >= timeout {
+            break;
+        }
+        tiny_std::thread::sleep(RETRY_INTERVAL)
+            .map_err(|e| Error::Md(format!("Failed to sleep waiting for {device}: {e}")))?;
+        waited += RETRY_INTERVAL;
+    }
+    match array.degraded {
+        DegradedPolicy::Fail => Err(Error::Md(format!(
+            "Failed to assemble {device} with all members within {}s",
+            array.timeout_secs
+        ))),
+        DegradedPolicy::Start => {
+            unix_println!("Starting {device} degraded, not all members showed up.");
+            let res = mdadm(&["--assemble", "--run", &device, &uuid])?;
+            if res != 0 {
+                return Err(Error::Md(format!(
+                    "Failed to start {device} degraded, mdadm exited with {res}"
+                )));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn mdadm(args: &[&str]) -> Result<i32> {
+    let mut cmd = Command::new(MDADM)
+        .map_err(|e| Error::Md(format!("Failed to instantiate command {MDADM} {e}")))?;
+    for arg in args {
+        cmd.arg(arg).map_err(|e| {
+            Error::Md(format!(
+                "Failed to instantiate command {MDADM} adding arg {arg}: {e}"
+            ))
+        })?;
+    }
+    cmd.stdout(Stdio::Null)
+        .spawn()
+        .map_err(|e| Error::Md(format!("Failed to spawn {MDADM} {e}")))?
+        .wait()
+        .map_err(|e| Error::Md(format!("Failed to await for child process {MDADM}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn md_uuids() {
+        assert!(is_md_uuid("3b1a4e6c:8d2f5a90:1c7e4b3d:9f0a2e6b"));
+        assert!(is_md_uuid("3b1a4e6c-8d2f-5a90-1c7e-4b3d9f0a2e6b"));
+        assert!(!is_md_uuid("3b1a4e6c:8d2f5a90:1c7e4b3d"));
+        assert!(!is_md_uuid("3b1a4e6c:8d2f5a90:1c7e4b3d:9f0a2e6x"));
+    }
+}
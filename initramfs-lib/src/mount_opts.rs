@@ -0,0 +1,264 @@
+//! fstab style mount options, split into `mount(2)` flags and the filesystem specific data string
+//! the way `mount(8)` does it.
+use crate::error::{Error, Result};
+use alloc::format;
+use alloc::string::String;
+
+pub const MS_RDONLY: u64 = 1;
+pub const MS_NOSUID: u64 = 1 << 1;
+pub const MS_NODEV: u64 = 1 << 2;
+pub const MS_NOEXEC: u64 = 1 << 3;
+pub const MS_SYNCHRONOUS: u64 = 1 << 4;
+pub const MS_MANDLOCK: u64 = 1 << 6;
+pub const MS_DIRSYNC: u64 = 1 << 7;
+pub const MS_NOSYMFOLLOW: u64 = 1 << 8;
+pub const MS_NOATIME: u64 = 1 << 10;
+pub const MS_NODIRATIME: u64 = 1 << 11;
+/// Not an option, moves a mount to another mount point
+pub const MS_MOVE: u64 = 1 << 13;
+pub const MS_SILENT: u64 = 1 << 15;
+pub const MS_RELATIME: u64 = 1 << 21;
+pub const MS_I_VERSION: u64 = 1 << 23;
+pub const MS_STRICTATIME: u64 = 1 << 24;
+pub const MS_LAZYTIME: u64 = 1 << 25;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MountOptions {
+    pub flags: u64,
+    /// Comma separated options passed through to the filesystem, `None` if there are none
+    pub data: Option<String>,
+}
+
+/// Options that set (`true`) or clear (`false`) a flag.
+const FLAG_OPTIONS: &[(&str, u64, bool)] = &[
+    ("ro", MS_RDONLY, true),
+    ("rw", MS_RDONLY, false),
+    ("nosuid", MS_NOSUID, true),
+    ("suid", MS_NOSUID, false),
+    ("nodev", MS_NODEV, true),
+    ("dev", MS_NODEV, false),
+    ("noexec", MS_NOEXEC, true),
+    ("exec", MS_NOEXEC, false),
+    ("sync", MS_SYNCHRONOUS, true),
+    ("async", MS_SYNCHRONOUS, false),
+    ("mand", MS_MANDLOCK, true),
+    ("nomand", MS_MANDLOCK, false),
+    ("dirsync", MS_DIRSYNC, true),
+    ("nosymfollow", MS_NOSYMFOLLOW, true),
+    ("symfollow", MS_NOSYMFOLLOW, false),
+    ("noatime", MS_NOATIME, true),
+    ("atime", MS_NOATIME, false),
+    ("nodiratime", MS_NODIRATIME, true),
+    ("diratime", MS_NODIRATIME, false),
+    ("silent", MS_SILENT, true),
+    ("loud", MS_SILENT, false),
+    ("relatime", MS_RELATIME, true),
+    ("norelatime", MS_RELATIME, false),
+    ("iversion", MS_I_VERSION, true),
+    ("noiversion", MS_I_VERSION, false),
+    ("strictatime", MS_STRICTATIME, true),
+    ("nostrictatime", MS_STRICTATIME, false),
+    ("lazytime", MS_LAZYTIME, true),
+    ("nolazytime", MS_LAZYTIME, false),
+];
+
+/// Only meaningful to `mount(8)`, `fsck` or the init system, nothing to do for them here.
+const USERSPACE_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "nofail", "user", "nouser", "users", "owner", "group", "_netdev",
+];
+
+/// Filesystem options without a value.
+const DATA_OPTIONS: &[&str] = &[
+    // Common
+    "discard",
+    "nodiscard",
+    "acl",
+    "noacl",
+    "user_xattr",
+    "nouser_xattr",
+    "barrier",
+    "nobarrier",
+    // ext4
+    "journal_checksum",
+    "nojournal_checksum",
+    "journal_async_commit",
+    "delalloc",
+    "nodelalloc",
+    "dioread_lock",
+    "dioread_nolock",
+    "noload",
+    "nombcache",
+    "auto_da_alloc",
+    "noauto_da_alloc",
+    "quota",
+    "noquota",
+    "usrquota",
+    "grpquota",
+    "prjquota",
+    // btrfs
+    "autodefrag",
+    "noautodefrag",
+    "compress",
+    "compress-force",
+    "datacow",
+    "nodatacow",
+    "datasum",
+    "nodatasum",
+    "degraded",
+    "flushoncommit",
+    "noflushoncommit",
+    "space_cache",
+    "nospace_cache",
+    "ssd",
+    "ssd_spread",
+    "nossd",
+    "nossd_spread",
+    "skip_balance",
+    "treelog",
+    "notreelog",
+    "rescan_uuid_tree",
+    "clear_cache",
+    // xfs
+    "attr2",
+    "noattr2",
+    "inode32",
+    "inode64",
+    "largeio",
+    "nolargeio",
+    "norecovery",
+    "wsync",
+];
+
+/// Keys of filesystem options of the form `key=value`, the value is passed through as is.
+const DATA_KEYS: &[&str] = &[
+    // Common
+    "errors",
+    "commit",
+    "discard",
+    "barrier",
+    "uid",
+    "gid",
+    "umask",
+    "mode",
+    "size",
+    "context",
+    "fscontext",
+    "defcontext",
+    "rootcontext",
+    // ext4
+    "data",
+    "data_err",
+    "journal",
+    "journal_dev",
+    "journal_path",
+    "journal_ioprio",
+    "resuid",
+    "resgid",
+    "sb",
+    "stripe",
+    "inode_readahead_blks",
+    "min_batch_time",
+    "max_batch_time",
+    "init_itable",
+    "auto_da_alloc",
+    "usrjquota",
+    "grpjquota",
+    "jqfmt",
+    "dax",
+    // btrfs
+    "compress",
+    "compress-force",
+    "subvol",
+    "subvolid",
+    "device",
+    "space_cache",
+    "thread_pool",
+    "max_inline",
+    "metadata_ratio",
+    "fatal_errors",
+    "rescue",
+    // xfs
+    "logbufs",
+    "logbsize",
+    "logdev",
+    "rtdev",
+    "allocsize",
+    "sunit",
+    "swidth",
+    // vfat
+    "fmask",
+    "dmask",
+    "codepage",
+    "iocharset",
+    "shortname",
+    "utf8",
+];
+
+pub fn parse_mount_options<S: AsRef<str>>(options: &[S]) -> Result<MountOptions> {
+    let mut parsed = MountOptions::default();
+    for opt in options {
+        let opt = opt.as_ref();
+        if let Some((_, flag, set)) = FLAG_OPTIONS.iter().find(|(name, _, _)| *name == opt) {
+            if *set {
+                parsed.flags |= flag;
+            } else {
+                parsed.flags &= !flag;
+            }
+            continue;
+        }
+        if USERSPACE_OPTIONS.contains(&opt)
+            || opt.starts_with("x-")
+            || opt.starts_with("X-")
+            || opt.starts_with("comment=")
+        {
+            continue;
+        }
+        let known = match opt.split_once('=') {
+            Some((key, _)) => DATA_KEYS.contains(&key),
+            None => DATA_OPTIONS.contains(&opt),
+        };
+        if !known {
+            return Err(Error::Cfg(format!("Unknown mount option '{opt}'")));
+        }
+        let data = parsed.data.get_or_insert_with(String::new);
+        if !data.is_empty() {
+            data.push(',');
+        }
+        data.push_str(opt);
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_flags_and_data() {
+        let opts = parse_mount_options(&[
+            "defaults",
+            "ro",
+            "noatime",
+            "nodev",
+            "nosuid",
+            "discard",
+            "compress=zstd:3",
+            "subvol=@",
+            "x-systemd.device-timeout=0",
+            "nofail",
+        ])
+        .unwrap();
+        assert_eq!(MS_RDONLY | MS_NOATIME | MS_NODEV | MS_NOSUID, opts.flags);
+        assert_eq!(
+            Some("discard,compress=zstd:3,subvol=@"),
+            opts.data.as_deref()
+        );
+        let opts = parse_mount_options(&["ro", "rw", "relatime"]).unwrap();
+        assert_eq!(MS_RELATIME, opts.flags);
+        assert_eq!(None, opts.data);
+        assert!(parse_mount_options(&["noatim"]).is_err());
+        assert!(parse_mount_options(&["=zstd"]).is_err());
+        assert!(parse_mount_options(&["comprss=zstd"]).is_err());
+        assert!(parse_mount_options(&["sbvol=@"]).is_err());
+    }
+}
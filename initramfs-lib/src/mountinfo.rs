@@ -0,0 +1,87 @@
+//! Parser for `/proc/self/mountinfo`, see proc(5).
+use crate::error::{Error, Result};
+use crate::tab::unescape_octal;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    /// `major:minor` of the mounted device, `0:x` for anything without a single backing device
+    pub major_minor: String,
+    /// The root of the mount within the filesystem, ie. the btrfs subvolume
+    pub root: String,
+    pub mount_point: String,
+    pub fs_type: String,
+    pub source: String,
+    pub super_options: String,
+}
+
+pub fn read_mountinfo() -> Result<Vec<MountInfo>> {
+    let content = tiny_std::fs::read_to_string("/proc/self/mountinfo\0")
+        .map_err(|e| Error::MountInfo(format!("Failed to read /proc/self/mountinfo: {e}")))?;
+    parse_mountinfo(&content)
+}
+
+pub fn parse_mountinfo(content: &str) -> Result<Vec<MountInfo>> {
+    let mut mounts = Vec::new();
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+        let (pre, post) = line
+            .split_once(" - ")
+            .ok_or_else(|| Error::MountInfo(format!("No separator in mountinfo line '{line}'")))?;
+        let mut pre = pre.split(' ');
+        let (Some(_id), Some(_parent), Some(major_minor), Some(root), Some(mount_point)) =
+            (pre.next(), pre.next(), pre.next(), pre.next(), pre.next())
+        else {
+            return Err(Error::MountInfo(format!(
+                "Too few fields in mountinfo line '{line}'"
+            )));
+        };
+        let mut post = post.split(' ');
+        let (Some(fs_type), Some(source)) = (post.next(), post.next()) else {
+            return Err(Error::MountInfo(format!(
+                "Too few fields after separator in mountinfo line '{line}'"
+            )));
+        };
+        mounts.push(MountInfo {
+            major_minor: major_minor.to_string(),
+            root: unescape_octal(root),
+            mount_point: unescape_octal(mount_point),
+            fs_type: fs_type.to_string(),
+            source: unescape_octal(source),
+            super_options: post.next().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(mounts)
+}
+
+/// The last mount on a mount point is the visible one.
+pub fn find_mount<'a>(mounts: &'a [MountInfo], mount_point: &str) -> Option<&'a MountInfo> {
+    mounts.iter().rev().find(|m| m.mount_point == mount_point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mountinfo() {
+        let content = "22 1 253:0 / / rw,noatime shared:1 - ext4 /dev/mapper/croot rw\n\
+        23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:5 - proc proc rw\n\
+        40 22 0:35 /@home /home\\040dir rw,relatime shared:20 - btrfs /dev/mapper/chome rw,subvol=/@home\n";
+        let mounts = parse_mountinfo(content).unwrap();
+        assert_eq!(3, mounts.len());
+        let root = find_mount(&mounts, "/").unwrap();
+        assert_eq!("253:0", root.major_minor);
+        assert_eq!("/dev/mapper/croot", root.source);
+        assert_eq!("ext4", root.fs_type);
+        let home = find_mount(&mounts, "/home dir").unwrap();
+        assert_eq!("/@home", home.root);
+        assert_eq!("rw,subvol=/@home", home.super_options);
+        assert!(parse_mountinfo("garbage\n").is_err());
+    }
+}
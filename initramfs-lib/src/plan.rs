@@ -0,0 +1,451 @@
+//! The order everything in a [`Cfg`] is brought up in. Arrays, volumes, volume groups, mounts and
+//! swaps are nodes of a dependency graph: volumes sit on arrays, logical volumes on unlocked
+//! physical volumes, mounts on their sources and the mount they're nested in, and volumes with
+//! their key on a mount on that mount. Swap files sit on their mount, resuming from one only on
+//! the device under it.
+use crate::cfg::{self, Cfg, CryptVolume, MdArray, Mount, Swap, Zram};
+use crate::error::{Error, Result};
+use crate::lvm::parse_lv;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Step<'a> {
+    Assemble(&'a MdArray),
+    Unlock(&'a CryptVolume),
+    /// Volume group name
+    Activate(&'a str),
+    Mount(&'a Mount),
+    Swapon(&'a Swap),
+    /// Resuming from a swap file, on the device of the mount it's on
+    Resume(&'a Swap, &'a Mount),
+    /// Created, formatted and turned on
+    Zram(&'a Zram),
+}
+
+/// A step and the steps it directly depends on, it's only run if those succeeded.
+#[derive(Debug, Clone)]
+pub struct Planned<'a> {
+    pub step: Step<'a>,
+    pub after: Vec<Step<'a>>,
+    /// Steps it's ordered after but doesn't need, swaps before mounts and `nofail` volumes that
+    /// could be physical volumes before volume groups
+    pub waits_for: Vec<Step<'a>>,
+}
+
+impl Step<'_> {
+    pub fn describe(&self) -> String {
+        match self {
+            Step::Assemble(md) => format!("md.{}", md.name),
+            Step::Unlock(crypt) => format!("crypt.{}", crypt.name),
+            Step::Activate(vg) => format!("volume group {vg}"),
+            Step::Mount(mount) => format!("mount.{}", mount.name),
+            Step::Swapon(swap) => format!("swap.{}", swap.name),
+            Step::Resume(swap, _) => format!("resume from swap.{}", swap.name),
+            Step::Zram(zram) => format!("zram.{}", zram.name),
+        }
+    }
+
+    /// `nofail` volumes, mounts and swaps are skipped if they fail, root never is. zram is never
+    /// needed to boot.
+    pub fn is_optional(&self) -> bool {
+        let nofail = |options: &[String]| options.iter().any(|opt| opt == "nofail");
+        match self {
+            Step::Assemble(_) | Step::Activate(_) => false,
+            Step::Unlock(crypt) => nofail(&crypt.options),
+            Step::Mount(mount) => mount.target != "/" && nofail(&mount.options),
+            Step::Swapon(swap) | Step::Resume(swap, _) => nofail(&swap.options),
+            Step::Zram(_) => true,
+        }
+    }
+}
+
+/// Topologically sorted steps. Of the steps that are ready the one listed first in the cfg is
+/// taken, so arrays come before volumes and volumes before mounts. Swaps and swap file resumes that
+/// don't wait for a mount come before every mount, they could hold a hibernation image to resume
+/// from, see [`crate::resume`].
+pub fn plan(cfg: &Cfg) -> Result<Vec<Planned<'_>>> {
+    let mut steps = Vec::new();
+    steps.extend(cfg.md.iter().map(Step::Assemble));
+    steps.extend(cfg.crypt.iter().map(Step::Unlock));
+    steps.extend(cfg.volume_groups().into_iter().map(Step::Activate));
+    steps.extend(cfg.mounts_in_order().into_iter().map(Step::Mount));
+    steps.extend(cfg.swaps.iter().map(Step::Swapon));
+    for swap in cfg.swaps.iter().filter(|s| s.resume_offset.is_some()) {
+        steps.push(Step::Resume(swap, swap_file_mount(cfg, swap)?));
+    }
+    steps.extend(cfg.zram.iter().map(Step::Zram));
+    let deps = steps
+        .iter()
+        .map(|step| dependencies(&steps, step))
+        .collect::<Result<Vec<_>>>()?;
+    // Edges that only order steps, a mount doesn't need a swap that failed
+    let mut ordering = deps.clone();
+    let early_swaps = (0..steps.len())
+        .filter(|&ind| {
+            matches!(steps[ind], Step::Swapon(_) | Step::Resume(..))
+                && !waits_for_mount(&steps, &deps, ind)
+        })
+        .collect::<Vec<_>>();
+    let optional_pvs = (0..steps.len())
+        .filter(|&ind| may_be_pv(&steps, &steps[ind]) && steps[ind].is_optional())
+        .collect::<Vec<_>>();
+    for (ind, step) in steps.iter().enumerate() {
+        match step {
+            Step::Mount(_) => ordering[ind].extend_from_slice(&early_swaps),
+            // Activated with whatever came up, its mounts fail on their own if something's missing
+            Step::Activate(_) => ordering[ind].extend_from_slice(&optional_pvs),
+            _ => {}
+        }
+    }
+    let mut done = vec![false; steps.len()];
+    let mut order = Vec::with_capacity(steps.len());
+    while order.len() < steps.len() {
+        let Some(next) =
+            (0..steps.len()).find(|&ind| !done[ind] && ordering[ind].iter().all(|&dep| done[dep]))
+        else {
+            let stuck = (0..steps.len())
+                .filter(|&ind| !done[ind])
+                .map(|ind| steps[ind].describe())
+                .collect::<Vec<_>>();
+            return Err(Error::Cfg(format!(
+                "Dependency cycle between {}",
+                stuck.join(", ")
+            )));
+        };
+        done[next] = true;
+        order.push(Planned {
+            step: steps[next],
+            after: deps[next].iter().map(|&dep| steps[dep]).collect(),
+            waits_for: ordering[next]
+                .iter()
+                .filter(|dep| !deps[next].contains(dep))
+                .map(|&dep| steps[dep])
+                .collect(),
+        });
+    }
+    Ok(order)
+}
+
+fn swap_file_mount<'a>(cfg: &'a Cfg, swap: &Swap) -> Result<&'a Mount> {
+    let name = swap.mount.as_deref().unwrap_or_default();
+    cfg.mounts.iter().find(|m| m.name == name).ok_or_else(|| {
+        Error::Cfg(format!(
+            "swap.{} is a swap file on missing mount {name}",
+            swap.name
+        ))
+    })
+}
+
+/// Volumes that could be physical volumes of a volume group, not ones with their key on a mount or
+/// with a filesystem or swap directly on them.
+fn may_be_pv(steps: &[Step], step: &Step) -> bool {
+    let Step::Unlock(crypt) = step else {
+        return false;
+    };
+    let member = format!("device=/dev/mapper/{}", crypt.name);
+    crypt.key_mount.is_none()
+        && !steps.iter().any(|s| match s {
+            Step::Mount(m) => m.source == crypt.name || m.options.contains(&member),
+            Step::Swapon(s) => s.source == crypt.name,
+            _ => false,
+        })
+}
+
+/// Whether the step at `ind` needs a mount, directly or through its dependencies.
+fn waits_for_mount(steps: &[Step], deps: &[Vec<usize>], ind: usize) -> bool {
+    let mut seen = vec![false; steps.len()];
+    let mut stack = vec![ind];
+    while let Some(cur) = stack.pop() {
+        for &dep in &deps[cur] {
+            if matches!(steps[dep], Step::Mount(_)) {
+                return true;
+            }
+            if !seen[dep] {
+                seen[dep] = true;
+                stack.push(dep);
+            }
+        }
+    }
+    false
+}
+
+fn dependencies(steps: &[Step], step: &Step) -> Result<Vec<usize>> {
+    let find = |pred: &dyn Fn(&Step) -> bool| steps.iter().position(pred);
+    let arrays = || {
+        steps
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| matches!(s, Step::Assemble(_)))
+            .map(|(ind, _)| ind)
+    };
+    let mut deps = Vec::new();
+    match step {
+        Step::Assemble(_) | Step::Zram(_) => {}
+        Step::Unlock(crypt) => {
+            // LUKS can be on an array
+            deps.extend(arrays());
+            if let Some(key_mount) = &crypt.key_mount {
+                deps.push(
+                    find(&|s| matches!(s, Step::Mount(m) if &m.name == key_mount)).ok_or_else(
+                        || {
+                            Error::Cfg(format!(
+                                "crypt.{} has its key on missing mount {key_mount}",
+                                crypt.name
+                            ))
+                        },
+                    )?,
+                );
+            }
+        }
+        Step::Activate(_) => {
+            // Which volumes are physical volumes isn't known, see `may_be_pv`. `nofail` ones are
+            // only waited for, see `plan`
+            deps.extend(arrays());
+            deps.extend(
+                steps.iter().enumerate().filter_map(|(ind, s)| {
+                    (may_be_pv(steps, s) && !s.is_optional()).then_some(ind)
+                }),
+            );
+        }
+        Step::Mount(mount) => {
+            deps.extend(source(steps, &mount.name, &mount.source)?);
+            // Members of a multi-device btrfs
+            for member in mount
+                .options
+                .iter()
+                .filter_map(|opt| opt.strip_prefix("device=/dev/mapper/"))
+            {
+                if let Some(ind) = find(&|s| matches!(s, Step::Unlock(c) if c.name == member)) {
+                    deps.push(ind);
+                }
+            }
+            if mount.target != "/" {
+                let parent = steps
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(ind, s)| match s {
+                        Step::Mount(p)
+                            if p.target != mount.target
+                                && cfg::is_under(&mount.target, &p.target) =>
+                        {
+                            Some((ind, p.target.len()))
+                        }
+                        _ => None,
+                    })
+                    .max_by_key(|(_, len)| *len)
+                    .ok_or_else(|| {
+                        Error::Cfg(format!(
+                            "mount.{} at {} has no parent mount",
+                            mount.name, mount.target
+                        ))
+                    })?;
+                deps.push(parent.0);
+            }
+        }
+        Step::Swapon(swap) => match &swap.mount {
+            Some(mount) => {
+                deps.extend(find(&|s| matches!(s, Step::Mount(m) if &m.name == mount)));
+                deps.extend(find(
+                    &|s| matches!(s, Step::Resume(r, _) if r.name == swap.name),
+                ));
+            }
+            None => deps.extend(source(steps, &swap.name, &swap.source)?),
+        },
+        Step::Resume(swap, mount) => deps.extend(source(steps, &swap.name, &mount.source)?),
+    }
+    Ok(deps)
+}
+
+/// The step that makes `source` of the mount or swap `name` show up, device paths other than
+/// arrays are already there.
+fn source(steps: &[Step], name: &str, source: &str) -> Result<Option<usize>> {
+    if let Some(array) = source.strip_prefix("/dev/md/") {
+        return Ok(steps
+            .iter()
+            .position(|s| matches!(s, Step::Assemble(md) if md.name == array)));
+    }
+    if source.starts_with('/') {
+        return Ok(None);
+    }
+    let found = if let Some((vg, _)) = parse_lv(source) {
+        steps
+            .iter()
+            .position(|s| matches!(s, Step::Activate(v) if *v == vg))
+    } else {
+        steps
+            .iter()
+            .position(|s| matches!(s, Step::Unlock(c) if c.name == source))
+    };
+    found
+        .map(Some)
+        .ok_or_else(|| Error::Cfg(format!("{name} has missing source {source}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::{DegradedPolicy, PseudoFs, Unlock};
+    use alloc::string::ToString;
+
+    fn crypt(name: &str, key_mount: Option<&str>) -> CryptVolume {
+        CryptVolume {
+            name: name.to_string(),
+            uuid: String::new(),
+            key_file: None,
+            key_mount: key_mount.map(ToString::to_string),
+            options: Vec::new(),
+            unlock: None,
+            header_sha256: None,
+        }
+    }
+
+    fn mount(name: &str, source: &str, target: &str) -> Mount {
+        Mount {
+            name: name.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            fs_type: None,
+            options: Vec::new(),
+            subvol: None,
+        }
+    }
+
+    fn describe(cfg: &Cfg) -> Result<Vec<String>> {
+        Ok(plan(cfg)?.iter().map(|p| p.step.describe()).collect())
+    }
+
+    #[test]
+    fn orders_by_dependencies() {
+        let mut cfg = Cfg {
+            key_file_path: None,
+            unlock: Unlock::Cryptsetup,
+            pseudo_fs: PseudoFs::Unmount,
+            md: vec![MdArray {
+                name: "mirror".to_string(),
+                uuid: String::new(),
+                degraded: DegradedPolicy::Fail,
+                timeout_secs: 10,
+            }],
+            crypt: vec![crypt("home_crypt", Some("root")), crypt("pv_crypt", None)],
+            mounts: vec![
+                mount("data", "home_crypt", "/home/data"),
+                mount("home", "/dev/md/mirror", "/home"),
+                mount("root", "vg0/root", "/"),
+            ],
+            swaps: vec![Swap {
+                name: "swap".to_string(),
+                source: "vg0/swap".to_string(),
+                options: Vec::new(),
+                mount: None,
+                resume_offset: None,
+            }],
+            zram: Vec::new(),
+        };
+        assert_eq!(
+            vec![
+                "md.mirror",
+                "crypt.pv_crypt",
+                "volume group vg0",
+                "swap.swap",
+                "mount.root",
+                "crypt.home_crypt",
+                "mount.home",
+                "mount.data",
+            ],
+            describe(&cfg).unwrap()
+        );
+        let planned = plan(&cfg).unwrap();
+        let data = planned
+            .iter()
+            .find(|p| p.step.describe() == "mount.data")
+            .unwrap();
+        assert_eq!(
+            vec!["crypt.home_crypt", "mount.home"],
+            data.after.iter().map(Step::describe).collect::<Vec<_>>()
+        );
+        assert!(!data.step.is_optional());
+        cfg.mounts[0].options = vec!["nofail".to_string()];
+        cfg.mounts[2].options = vec!["nofail".to_string()];
+        assert!(Step::Mount(&cfg.mounts[0]).is_optional());
+        // Root always has to be there
+        assert!(!Step::Mount(&cfg.mounts[2]).is_optional());
+        // The key of data's volume on data
+        cfg.crypt[0].key_mount = Some("data".to_string());
+        let err = describe(&cfg).unwrap_err();
+        assert!(matches!(err, Error::Cfg(ref msg) if msg.contains("cycle")));
+        cfg.crypt[0].key_mount = Some("usr".to_string());
+        assert!(describe(&cfg).is_err());
+        cfg.crypt[0].key_mount = None;
+        cfg.mounts.pop();
+        assert!(describe(&cfg).is_err());
+    }
+
+    #[test]
+    fn volume_groups_wait_for_nofail_volumes() {
+        let mut cfg = Cfg {
+            key_file_path: None,
+            unlock: Unlock::Cryptsetup,
+            pseudo_fs: PseudoFs::Unmount,
+            md: Vec::new(),
+            crypt: vec![crypt("pv0", None), crypt("pv1", None)],
+            mounts: vec![mount("root", "vg0/root", "/")],
+            swaps: Vec::new(),
+            zram: Vec::new(),
+        };
+        cfg.crypt[1].options = vec!["nofail".to_string()];
+        let planned = plan(&cfg).unwrap();
+        assert_eq!(
+            vec!["crypt.pv0", "crypt.pv1", "volume group vg0", "mount.root"],
+            planned
+                .iter()
+                .map(|p| p.step.describe())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["crypt.pv0"],
+            planned[2]
+                .after
+                .iter()
+                .map(Step::describe)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn resumes_swap_files_before_mounting() {
+        let swap_file = |resume_offset| Swap {
+            name: "swapfile".to_string(),
+            source: "/swapfile".to_string(),
+            options: Vec::new(),
+            mount: Some("root".to_string()),
+            resume_offset,
+        };
+        let mut cfg = Cfg {
+            key_file_path: None,
+            unlock: Unlock::Cryptsetup,
+            pseudo_fs: PseudoFs::Unmount,
+            md: Vec::new(),
+            crypt: vec![crypt("croot", None)],
+            mounts: vec![mount("root", "croot", "/")],
+            swaps: vec![swap_file(Some(34816))],
+            zram: Vec::new(),
+        };
+        assert_eq!(
+            vec![
+                "crypt.croot",
+                "resume from swap.swapfile",
+                "mount.root",
+                "swap.swapfile",
+            ],
+            describe(&cfg).unwrap()
+        );
+        cfg.swaps[0] = swap_file(None);
+        assert_eq!(
+            vec!["crypt.croot", "mount.root", "swap.swapfile"],
+            describe(&cfg).unwrap()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Resuming from a hibernation image on swap. The kernel checks for an image when the swap's
+//! `major:minor` is written to `/sys/power/resume`, so this has to happen after the swap is
+//! unlocked and before anything is mounted.
+use crate::error::{Error, Result};
+use crate::swap::PAGE_SIZE;
+use crate::sys;
+use alloc::format;
+use alloc::string::{String, ToString};
+use tiny_std::fs::{File, OpenOptions};
+use tiny_std::unix::fd::AsRawFd;
+
+const SIG_LEN: usize = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SwapSignature {
+    Swap,
+    /// An image written by the kernel (swsusp) or uswsusp
+    Hibernation,
+    Unknown,
+}
+
+/// What the swap header of `device`, starting `offset` bytes in, says it contains.
+pub fn swap_signature(device: &str, offset: u64) -> Result<SwapSignature> {
+    let file =
+        File::open(device).map_err(|e| Error::Resume(format!("Failed to open {device}: {e}")))?;
+    let tail = read_page_tail(&file, device, offset)?;
+    Ok(signature_kind(&tail[SIG_LEN..]))
+}
+
+fn signature_kind(sig: &[u8]) -> SwapSignature {
+    if sig == b"SWAPSPACE2" || sig == b"SWAP-SPACE" {
+        SwapSignature::Swap
+    } else if [b"S1SUSPEND".as_slice(), b"ULSUSPEND", b"LINHIB0001"]
+        .iter()
+        .any(|hib| sig.starts_with(hib))
+    {
+        SwapSignature::Hibernation
+    } else {
+        SwapSignature::Unknown
+    }
+}
+
+/// The original signature and the current one.
+fn read_page_tail(file: &File, device: &str, offset: u64) -> Result<[u8; 2 * SIG_LEN]> {
+    let mut tail = [0u8; 2 * SIG_LEN];
+    let at = offset + PAGE_SIZE - tail.len() as u64;
+    let read = sys::pread(file.as_raw_fd(), &mut tail, at)
+        .map_err(|e| Error::Resume(format!("Failed to read swap header of {device}: {e:?}")))?;
+    if read != tail.len() {
+        return Err(Error::Resume(format!(
+            "{device} is too small to hold a swap header"
+        )));
+    }
+    Ok(tail)
+}
+
+/// Whether the kernel command line leaves resuming on, `noresume` turns it off.
+pub fn resume_enabled() -> Result<bool> {
+    let cmdline = tiny_std::fs::read_to_string("/proc/cmdline")
+        .map_err(|e| Error::Resume(format!("Failed to read /proc/cmdline: {e}")))?;
+    Ok(!cmdline.split_whitespace().any(|arg| arg == "noresume"))
+}
+
+/// Asks the kernel to restore the image on `device`, `offset` is in pages for swapfiles. Only
+/// returns if it couldn't.
+pub fn resume(device: &str, offset: Option<u64>) -> Error {
+    let dev = match device_number(device) {
+        Ok(dev) => dev,
+        Err(e) => return e,
+    };
+    if let Some(offset) = offset {
+        if let Err(e) =
+            tiny_std::fs::write("/sys/power/resume_offset", offset.to_string().as_bytes())
+        {
+            return Error::Resume(format!(
+                "Failed to write {offset} to /sys/power/resume_offset: {e}"
+            ));
+        }
+    }
+    match tiny_std::fs::write("/sys/power/resume", dev.as_bytes()) {
+        Ok(()) => Error::Resume(format!(
+            "Kernel didn't resume from the image on {device} ({dev})"
+        )),
+        Err(e) => Error::Resume(format!(
+            "Failed to write {dev} to /sys/power/resume for {device}: {e}"
+        )),
+    }
+}
+
+/// Puts back the swap signature the image replaced, so that the swap can be used again. The
+/// image is lost.
+pub fn discard_image(device: &str, offset: u64) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .map_err(|e| Error::Resume(format!("Failed to open {device} for writing: {e}")))?;
+    let tail = read_page_tail(&file, device, offset)?;
+    let orig = &tail[..SIG_LEN];
+    if signature_kind(orig) != SwapSignature::Swap {
+        return Err(Error::Resume(format!(
+            "The hibernation image on {device} doesn't record a swap signature to restore"
+        )));
+    }
+    let at = offset + PAGE_SIZE - SIG_LEN as u64;
+    let written = sys::pwrite(file.as_raw_fd(), orig, at).map_err(|e| {
+        Error::Resume(format!(
+            "Failed to restore swap signature on {device}: {e:?}"
+        ))
+    })?;
+    if written != SIG_LEN {
+        return Err(Error::Resume(format!(
+            "Short write restoring swap signature on {device}"
+        )));
+    }
+    Ok(())
+}
+
+/// `major:minor` of the block device at `path`.
+pub fn device_number(path: &str) -> Result<String> {
+    let stat = rusl::unistd::stat(path)
+        .map_err(|e| Error::Resume(format!("Failed to stat {path}: {e}")))?;
+    let dev = stat.st_rdev;
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    Ok(format!("{major}:{minor}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_signatures() {
+        assert_eq!(SwapSignature::Swap, signature_kind(b"SWAPSPACE2"));
+        assert_eq!(SwapSignature::Swap, signature_kind(b"SWAP-SPACE"));
+        assert_eq!(SwapSignature::Hibernation, signature_kind(b"S1SUSPEND\0"));
+        assert_eq!(SwapSignature::Hibernation, signature_kind(b"ULSUSPEND\0"));
+        assert_eq!(SwapSignature::Unknown, signature_kind(&[0; SIG_LEN]));
+    }
+}
@@ -0,0 +1,288 @@
+//! Swap areas, the header `mkswap` writes and the flags `swapon` takes, from fstab style options,
+//! see swapon(8). Swap files are checked before they're turned on.
+use crate::error::{Error, Result};
+use crate::resume::{swap_signature, SwapSignature};
+use crate::sys;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use tiny_std::fs::{File, OpenOptions};
+use tiny_std::unix::fd::AsRawFd;
+
+/// The header takes up the first page, its signature is in the last 10 bytes of it.
+pub const PAGE_SIZE: u64 = 4096;
+const SWAP_FLAG_PREFER: i32 = 0x8000;
+const SWAP_FLAG_PRIO_MASK: i32 = 0x7fff;
+const SWAP_FLAG_DISCARD: i32 = 0x10000;
+const SWAP_FLAG_DISCARD_ONCE: i32 = 0x20000;
+const SWAP_FLAG_DISCARD_PAGES: i32 = 0x40000;
+/// The kernel refuses anything smaller.
+const MIN_PAGES: u64 = 10;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// Unknown, delayed, encoded, unaligned, inline, tail packed, unwritten or shared extents, the
+/// kernel won't swap to those.
+const UNUSABLE_EXTENT: u32 = 0x2 | 0x4 | 0x8 | 0x100 | 0x200 | 0x400 | 0x800 | 0x2000;
+
+/// Only meaningful to the init system or `swapon -a`.
+const USERSPACE_OPTIONS: &[&str] = &["defaults", "sw", "auto", "noauto", "nofail", "_netdev"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Discard {
+    /// Both of the below
+    All,
+    /// The whole area when turned on
+    Once,
+    /// Freed pages as they're freed
+    Pages,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct SwapOptions {
+    /// 0 to 32767, higher is used first, the kernel picks a priority if not set
+    pub priority: Option<u16>,
+    pub discard: Option<Discard>,
+}
+
+impl SwapOptions {
+    pub fn flags(&self) -> i32 {
+        let mut flags = self.priority.map_or(0, priority_flags);
+        flags |= match self.discard {
+            None => 0,
+            Some(Discard::All) => SWAP_FLAG_DISCARD,
+            Some(Discard::Once) => SWAP_FLAG_DISCARD | SWAP_FLAG_DISCARD_ONCE,
+            Some(Discard::Pages) => SWAP_FLAG_DISCARD | SWAP_FLAG_DISCARD_PAGES,
+        };
+        flags
+    }
+}
+
+/// `swapon` flags for a fixed priority.
+pub fn priority_flags(priority: u16) -> i32 {
+    SWAP_FLAG_PREFER | (i32::from(priority) & SWAP_FLAG_PRIO_MASK)
+}
+
+pub fn parse_swap_options<S: AsRef<str>>(options: &[S]) -> Result<SwapOptions> {
+    let mut parsed = SwapOptions::default();
+    for opt in options {
+        let opt = opt.as_ref();
+        if USERSPACE_OPTIONS.contains(&opt) || opt.starts_with("x-") {
+            continue;
+        }
+        match opt.split_once('=') {
+            None if opt == "discard" => parsed.discard = Some(Discard::All),
+            Some(("discard", "once")) => parsed.discard = Some(Discard::Once),
+            Some(("discard", "pages")) => parsed.discard = Some(Discard::Pages),
+            Some(("pri", value)) => {
+                parsed.priority =
+                    Some(value.parse().ok().filter(|p| *p <= 32767).ok_or_else(|| {
+                        Error::Swap(format!(
+                            "Swap option pri needs a priority from 0 to 32767, found '{value}'"
+                        ))
+                    })?);
+            }
+            _ => return Err(Error::Swap(format!("Unknown swap option '{opt}'"))),
+        }
+    }
+    Ok(parsed)
+}
+
+/// A version 1 header without bad pages for an area of `size` bytes, `label` is cut to 15 bytes.
+pub fn swap_header(size: u64, label: &str) -> Result<Vec<u8>> {
+    let pages = size / PAGE_SIZE;
+    if pages < MIN_PAGES {
+        return Err(Error::Swap(format!(
+            "A swap area needs at least {MIN_PAGES} pages, {size} bytes is {pages}"
+        )));
+    }
+    let last_page = u32::try_from(pages - 1).map_err(|_| {
+        Error::Swap(format!(
+            "{size} bytes is too large for a swap area with {PAGE_SIZE} byte pages"
+        ))
+    })?;
+    let mut header = vec![0u8; PAGE_SIZE as usize];
+    // After 1024 bytes of boot bits: version, last page, number of bad pages, uuid, label
+    header[1024..1028].copy_from_slice(&1u32.to_ne_bytes());
+    header[1028..1032].copy_from_slice(&last_page.to_ne_bytes());
+    let label = &label.as_bytes()[..label.len().min(15)];
+    header[1052..1052 + label.len()].copy_from_slice(label);
+    header[PAGE_SIZE as usize - 10..].copy_from_slice(b"SWAPSPACE2");
+    Ok(header)
+}
+
+/// A FIEMAP extent, `length` bytes of a file from byte `logical` on, stored at byte `physical` of
+/// its filesystem, which is the offset on the device unless the filesystem spans several.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+    /// `FIEMAP_EXTENT_*`
+    pub flags: u32,
+}
+
+/// Checks that the swap file at `path` can be turned on, a regular file with a swap signature and
+/// all of it allocated. Returns its extents.
+pub fn check_swap_file(path: &str) -> Result<Vec<Extent>> {
+    let meta = tiny_std::fs::metadata(path)
+        .map_err(|e| Error::Swap(format!("Failed to stat swap file {path}: {e}")))?;
+    if !meta.is_file() {
+        return Err(Error::Swap(format!(
+            "Swap file {path} isn't a regular file"
+        )));
+    }
+    if meta.len() < MIN_PAGES * PAGE_SIZE {
+        return Err(Error::Swap(format!(
+            "Swap file {path} is {} bytes, less than {MIN_PAGES} pages",
+            meta.len()
+        )));
+    }
+    match swap_signature(path, 0)? {
+        SwapSignature::Swap => {}
+        SwapSignature::Hibernation => {
+            return Err(Error::Swap(format!(
+                "Swap file {path} holds a hibernation image, set a resume_offset for it to be resumed from"
+            )))
+        }
+        SwapSignature::Unknown => {
+            return Err(Error::Swap(format!(
+                "Swap file {path} has no swap signature, format it with mkswap"
+            )))
+        }
+    }
+    let file = File::open(path)
+        .map_err(|e| Error::Swap(format!("Failed to open swap file {path}: {e}")))?;
+    let extents = file_extents(&file, path)?;
+    check_extents(&extents, meta.len(), path)?;
+    Ok(extents)
+}
+
+/// The page a swap file starts at on the device of its filesystem, what `resume_offset` takes.
+/// Only the same as the device offset on filesystems that map a single device 1:1, not btrfs.
+pub fn resume_offset(extents: &[Extent]) -> Option<u64> {
+    extents
+        .first()
+        .filter(|e| e.logical == 0)
+        .map(|e| e.physical / PAGE_SIZE)
+}
+
+fn file_extents(file: &File, path: &str) -> Result<Vec<Extent>> {
+    let mut extents: Vec<Extent> = Vec::new();
+    loop {
+        let start = extents.last().map_or(0, |e| e.logical + e.length);
+        let batch = sys::fiemap(file.as_raw_fd(), start)
+            .map_err(|e| Error::Swap(format!("Failed to map extents of {path}: {e:?}")))?;
+        let Some(last) = batch.last() else {
+            return Ok(extents);
+        };
+        let done = last.flags & FIEMAP_EXTENT_LAST != 0;
+        extents.extend(batch.iter().map(|e| Extent {
+            logical: e.logical,
+            physical: e.physical,
+            length: e.length,
+            flags: e.flags,
+        }));
+        if done {
+            return Ok(extents);
+        }
+    }
+}
+
+/// Every byte up to `len` has to be in an extent that's plainly on disk, swap can't have holes.
+fn check_extents(extents: &[Extent], len: u64, path: &str) -> Result<()> {
+    let mut end = 0;
+    for extent in extents {
+        if extent.logical > end {
+            return Err(Error::Swap(format!(
+                "Swap file {path} has a hole at byte {end}, recreate it with dd or fallocate"
+            )));
+        }
+        if extent.flags & UNUSABLE_EXTENT != 0 {
+            return Err(Error::Swap(format!(
+                "Swap file {path} has an extent at byte {} that can't be swapped to, flags {:#x}",
+                extent.logical, extent.flags
+            )));
+        }
+        end = end.max(extent.logical + extent.length);
+    }
+    if end < len {
+        return Err(Error::Swap(format!(
+            "Swap file {path} has a hole at byte {end}, recreate it with dd or fallocate"
+        )));
+    }
+    Ok(())
+}
+
+/// Formats `device` as swap, like `mkswap`.
+pub fn write_swap_header(device: &str, size: u64, label: &str) -> Result<()> {
+    let header = swap_header(size, label)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .map_err(|e| Error::Swap(format!("Failed to open {device} for writing: {e}")))?;
+    let written = sys::pwrite(file.as_raw_fd(), &header, 0)
+        .map_err(|e| Error::Swap(format!("Failed to write swap header to {device}: {e:?}")))?;
+    if written != header.len() {
+        return Err(Error::Swap(format!(
+            "Short write of swap header to {device}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_header() {
+        let header = swap_header(64 << 20, "zram0").unwrap();
+        assert_eq!(PAGE_SIZE as usize, header.len());
+        assert_eq!(
+            1,
+            u32::from_ne_bytes(header[1024..1028].try_into().unwrap())
+        );
+        assert_eq!(
+            16383,
+            u32::from_ne_bytes(header[1028..1032].try_into().unwrap())
+        );
+        assert_eq!(b"zram0\0", &header[1052..1058]);
+        assert_eq!(b"SWAPSPACE2", &header[4086..]);
+        assert!(swap_header(9 * PAGE_SIZE, "small").is_err());
+        assert_eq!(0x8000 | 100, priority_flags(100));
+    }
+
+    #[test]
+    fn parses_options() {
+        let opts = parse_swap_options(&["sw", "pri=10", "discard=once", "nofail"]).unwrap();
+        assert_eq!(Some(10), opts.priority);
+        assert_eq!(0x8000 | 10 | 0x10000 | 0x20000, opts.flags());
+        assert_eq!(0x10000, parse_swap_options(&["discard"]).unwrap().flags());
+        assert_eq!(0, parse_swap_options(&["defaults"]).unwrap().flags());
+        assert!(parse_swap_options(&["pri=40000"]).is_err());
+        assert!(parse_swap_options(&["discard=all"]).is_err());
+        assert!(parse_swap_options(&["noatime"]).is_err());
+    }
+
+    #[test]
+    fn checks_extents() {
+        let extent = |logical, physical, length, flags| Extent {
+            logical,
+            physical,
+            length,
+            flags,
+        };
+        let extents = [
+            extent(0, 1 << 30, 1 << 20, 0),
+            extent(1 << 20, 2 << 30, 1 << 20, FIEMAP_EXTENT_LAST),
+        ];
+        assert!(check_extents(&extents, 2 << 20, "/swapfile").is_ok());
+        assert_eq!(Some((1 << 30) / PAGE_SIZE), resume_offset(&extents));
+        // Past the last extent
+        assert!(check_extents(&extents, 3 << 20, "/swapfile").is_err());
+        let holey = [extent(0, 0, 1 << 20, 0), extent(2 << 20, 0, 1 << 20, 0)];
+        assert!(check_extents(&holey, 3 << 20, "/swapfile").is_err());
+        let unwritten = [extent(0, 0, 1 << 20, 0x800 | FIEMAP_EXTENT_LAST)];
+        assert!(check_extents(&unwritten, 1 << 20, "/swapfile").is_err());
+        assert_eq!(None, resume_offset(&holey[1..]));
+    }
+}
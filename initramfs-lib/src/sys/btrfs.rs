@@ -0,0 +1,59 @@
+//! Multi-device btrfs registration through `/dev/btrfs-control`, see `linux/btrfs.h`.
+use crate::error::{Error, Result};
+use alloc::format;
+use tiny_std::unix::fd::AsRawFd;
+
+const BTRFS_IOCTL_MAGIC: usize = 0x94;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+const IOC_WRITE: usize = 1;
+const IOC_READ: usize = 2;
+const BTRFS_IOC_SCAN_DEV: usize = ioc(IOC_WRITE, 4);
+const BTRFS_IOC_DEVICES_READY: usize = ioc(IOC_READ, 39);
+
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+const fn ioc(dir: usize, nr: usize) -> usize {
+    (dir << 30) | (core::mem::size_of::<VolArgs>() << 16) | (BTRFS_IOCTL_MAGIC << 8) | nr
+}
+
+/// Registers `device` as a btrfs member so that a filesystem spanning it can be mounted.
+pub(crate) fn scan_device(device: &str) -> Result<()> {
+    control_ioctl(BTRFS_IOC_SCAN_DEV, device)?;
+    Ok(())
+}
+
+/// Whether all members of the filesystem on `device` have been registered.
+pub(crate) fn devices_ready(device: &str) -> Result<bool> {
+    // 0 when the number of registered devices matches the total
+    Ok(control_ioctl(BTRFS_IOC_DEVICES_READY, device)? == 0)
+}
+
+fn control_ioctl(request: usize, device: &str) -> Result<usize> {
+    if device.len() > BTRFS_PATH_NAME_MAX {
+        return Err(Error::Mount(format!(
+            "btrfs device path {device} is longer than {BTRFS_PATH_NAME_MAX}"
+        )));
+    }
+    let mut args = VolArgs {
+        fd: 0,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    args.name[..device.len()].copy_from_slice(device.as_bytes());
+    let control = tiny_std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/btrfs-control\0")
+        .map_err(|e| Error::Mount(format!("Failed to open /dev/btrfs-control: {e}")))?;
+    unsafe {
+        rusl::ioctl::ioctl(
+            control.as_raw_fd(),
+            request,
+            core::ptr::addr_of_mut!(args) as usize,
+        )
+    }
+    .map_err(|e| Error::Mount(format!("btrfs ioctl {request:#x} on {device} failed: {e}")))
+}
@@ -0,0 +1,150 @@
+//! Thin syscall wrappers for what rusl doesn't cover.
+pub(crate) mod btrfs;
+
+use alloc::borrow::Cow;
+use alloc::format;
+use rusl::error::Errno;
+use rusl::platform::{is_syscall_error, Fd, AT_FDCWD};
+use sc::syscall;
+
+/// Like `rusl::unistd::mount` but with the filesystem type by name, rusl only knows a handful.
+pub(crate) fn mount(
+    source: &str,
+    target: &str,
+    fs_type: &str,
+    flags: u64,
+    data: Option<&str>,
+) -> core::result::Result<(), Errno> {
+    let source = null_terminated(source);
+    let target = null_terminated(target);
+    let fs_type = null_terminated(fs_type);
+    let data = data.map(null_terminated);
+    let data_ptr = data.as_deref().map_or(0, |d| d.as_ptr() as usize);
+    let res = unsafe {
+        syscall!(
+            MOUNT,
+            source.as_ptr(),
+            target.as_ptr(),
+            fs_type.as_ptr(),
+            flags,
+            data_ptr
+        )
+    };
+    check(res).map(|_| ())
+}
+
+/// Creates a block device node at `path` for the kernel encoded device number `dev`.
+pub(crate) fn mknod_block(path: &str, dev: u64) -> core::result::Result<(), Errno> {
+    const S_IFBLK: usize = 0o060_000;
+    let path = null_terminated(path);
+    let res = unsafe {
+        syscall!(
+            MKNODAT,
+            AT_FDCWD,
+            path.as_ptr(),
+            S_IFBLK | 0o600,
+            dev as u32
+        )
+    };
+    check(res).map(|_| ())
+}
+
+/// Reads into `buf` from `offset` of `fd` without moving its file offset.
+pub(crate) fn pread(fd: Fd, buf: &mut [u8], offset: u64) -> core::result::Result<usize, Errno> {
+    let res = unsafe { syscall!(PREAD64, fd.value(), buf.as_mut_ptr(), buf.len(), offset) };
+    check(res)
+}
+
+/// Writes `buf` at `offset` of `fd` without moving its file offset.
+pub(crate) fn pwrite(fd: Fd, buf: &[u8], offset: u64) -> core::result::Result<usize, Errno> {
+    let res = unsafe { syscall!(PWRITE64, fd.value(), buf.as_ptr(), buf.len(), offset) };
+    check(res)
+}
+
+/// Size in bytes of the block device open at `fd`.
+pub(crate) fn block_device_size(fd: Fd) -> core::result::Result<u64, Errno> {
+    // _IOR(0x12, 114, size_t)
+    const BLKGETSIZE64: usize = 0x8008_1272;
+    let mut size = 0u64;
+    let res = unsafe {
+        syscall!(
+            IOCTL,
+            fd.value(),
+            BLKGETSIZE64,
+            core::ptr::addr_of_mut!(size)
+        )
+    };
+    check(res).map(|_| size)
+}
+
+/// `struct fiemap_extent`
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct FiemapExtent {
+    pub(crate) logical: u64,
+    pub(crate) physical: u64,
+    pub(crate) length: u64,
+    reserved64: [u64; 2],
+    pub(crate) flags: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct fiemap` with room for [`FIEMAP_BATCH`] extents.
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; FIEMAP_BATCH],
+}
+
+const FIEMAP_BATCH: usize = 32;
+
+/// Up to [`FIEMAP_BATCH`] extents of the file open at `fd` from byte `start` on, flushed to disk
+/// first so that none are delayed.
+pub(crate) fn fiemap(
+    fd: Fd,
+    start: u64,
+) -> core::result::Result<alloc::vec::Vec<FiemapExtent>, Errno> {
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: usize = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 1;
+    let mut map = Fiemap {
+        start,
+        length: u64::MAX - start,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: FIEMAP_BATCH as u32,
+        reserved: 0,
+        extents: [FiemapExtent::default(); FIEMAP_BATCH],
+    };
+    let res = unsafe {
+        syscall!(
+            IOCTL,
+            fd.value(),
+            FS_IOC_FIEMAP,
+            core::ptr::addr_of_mut!(map)
+        )
+    };
+    check(res)?;
+    Ok(map.extents[..map.mapped_extents as usize].to_vec())
+}
+
+pub(crate) fn null_terminated(s: &str) -> Cow<'_, str> {
+    if s.ends_with('\0') {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("{s}\0"))
+    }
+}
+
+pub(crate) fn check(res: usize) -> core::result::Result<usize, Errno> {
+    if is_syscall_error(res) {
+        // Flip the errno
+        return Err(Errno::new(0 - res as i32));
+    }
+    Ok(res)
+}
@@ -0,0 +1,123 @@
+//! Block device information from `/proc/partitions` and sysfs.
+use crate::error::{Error, Result};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// A device-mapper device as the kernel sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmDevice {
+    /// `dm-0` etc.
+    pub kernel_name: String,
+    /// The mapper name, `croot` for `/dev/mapper/croot`
+    pub name: String,
+    /// Set by whoever created the mapping, `CRYPT-LUKS2-<uuid>-<name>` for cryptsetup
+    pub uuid: String,
+}
+
+impl DmDevice {
+    /// The uuid of the LUKS container this mapping was opened from, if it is one.
+    pub fn luks_uuid(&self) -> Option<String> {
+        luks_uuid_from_dm_uuid(&self.uuid)
+    }
+}
+
+/// Kernel names of all block devices, partitions included.
+pub fn block_devices() -> Result<Vec<String>> {
+    let content = tiny_std::fs::read_to_string("/proc/partitions")
+        .map_err(|e| Error::Sysfs(format!("Failed to read /proc/partitions: {e}")))?;
+    // major minor  #blocks  name
+    Ok(content
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(3))
+        .map(ToString::to_string)
+        .collect())
+}
+
+pub fn dm_devices() -> Result<Vec<DmDevice>> {
+    let mut devices = Vec::new();
+    for kernel_name in block_devices()? {
+        if !kernel_name.starts_with("dm-") {
+            continue;
+        }
+        devices.push(read_dm_device(
+            &format!("/sys/class/block/{kernel_name}"),
+            kernel_name,
+        )?);
+    }
+    Ok(devices)
+}
+
+/// Looks up the device-mapper device backing a `major:minor`, `None` if it isn't one.
+pub fn dm_device_by_major_minor(major_minor: &str) -> Option<DmDevice> {
+    let dir = format!("/sys/dev/block/{major_minor}");
+    let dev = tiny_std::fs::read_to_string(format!("{dir}/uevent")).ok()?;
+    let kernel_name = dev
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVNAME="))?
+        .to_string();
+    read_dm_device(&dir, kernel_name).ok()
+}
+
+/// Resolves `/dev/mapper/<name>` and `/dev/dm-<n>` paths.
+pub fn dm_device_by_path(path: &str) -> Result<Option<DmDevice>> {
+    if let Some(name) = path.strip_prefix("/dev/mapper/") {
+        return Ok(dm_devices()?.into_iter().find(|dev| dev.name == name));
+    }
+    if let Some(kernel_name) = path.strip_prefix("/dev/") {
+        if kernel_name.starts_with("dm-") {
+            return Ok(dm_devices()?
+                .into_iter()
+                .find(|dev| dev.kernel_name == kernel_name));
+        }
+    }
+    Ok(None)
+}
+
+fn read_dm_device(dir: &str, kernel_name: String) -> Result<DmDevice> {
+    let read = |file: &str| {
+        tiny_std::fs::read_to_string(format!("{dir}/dm/{file}"))
+            .map(|s| s.trim().to_string())
+            .map_err(|e| Error::Sysfs(format!("Failed to read {dir}/dm/{file}: {e}")))
+    };
+    Ok(DmDevice {
+        name: read("name")?,
+        uuid: read("uuid")?,
+        kernel_name,
+    })
+}
+
+/// `CRYPT-LUKS2-7e127144f930465684b4d6a27fa7caa6-croot` -> `7e127144-f930-4656-84b4-d6a27fa7caa6`
+pub fn luks_uuid_from_dm_uuid(dm_uuid: &str) -> Option<String> {
+    let rest = dm_uuid
+        .strip_prefix("CRYPT-LUKS2-")
+        .or_else(|| dm_uuid.strip_prefix("CRYPT-LUKS1-"))?;
+    let hex = rest.get(..32)?;
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn luks_uuid_from_cryptsetup_dm_uuid() {
+        assert_eq!(
+            Some("7e127144-f930-4656-84b4-d6a27fa7caa6".to_string()),
+            luks_uuid_from_dm_uuid("CRYPT-LUKS2-7e127144f930465684b4d6a27fa7caa6-croot")
+        );
+        assert_eq!(None, luks_uuid_from_dm_uuid("LVM-abcdef"));
+        assert_eq!(None, luks_uuid_from_dm_uuid("CRYPT-PLAIN-cswap"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Parsers for `/etc/fstab` and `/etc/crypttab`, see fstab(5) and crypttab(5).
+use crate::error::{Error, Result};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    /// `UUID=...`, `/dev/mapper/...` etc.
+    pub spec: String,
+    pub file: String,
+    pub vfstype: String,
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrypttabEntry {
+    /// The mapper name
+    pub name: String,
+    /// `UUID=...`, `/dev/...` etc.
+    pub device: String,
+    /// `None` if absent, `none` or `-`, meaning a passphrase should be asked for
+    pub key_file: Option<String>,
+    pub options: Vec<String>,
+}
+
+impl CrypttabEntry {
+    /// The LUKS uuid if the device is specified through one.
+    pub fn uuid(&self) -> Option<&str> {
+        spec_uuid(&self.device)
+    }
+}
+
+/// Extracts the uuid from `UUID=<uuid>` or `/dev/disk/by-uuid/<uuid>`.
+pub fn spec_uuid(spec: &str) -> Option<&str> {
+    spec.strip_prefix("UUID=")
+        .or_else(|| spec.strip_prefix("/dev/disk/by-uuid/"))
+        .map(|uuid| uuid.trim_matches('"'))
+}
+
+pub fn parse_fstab(content: &str) -> Result<Vec<FstabEntry>> {
+    let mut entries = vec![];
+    for (ind, line) in content.lines().enumerate() {
+        let Some(fields) = fields(line) else {
+            continue;
+        };
+        if fields.len() < 3 {
+            return Err(Error::Tab(format!(
+                "Expected at least 3 fields in fstab entry [{ind}]: '{line}'"
+            )));
+        }
+        entries.push(FstabEntry {
+            spec: fields[0].clone(),
+            file: fields[1].clone(),
+            vfstype: fields[2].clone(),
+            options: fields
+                .get(3)
+                .map(|opts| split_options(opts))
+                .unwrap_or_default(),
+        });
+    }
+    Ok(entries)
+}
+
+pub fn parse_crypttab(content: &str) -> Result<Vec<CrypttabEntry>> {
+    let mut entries = vec![];
+    for (ind, line) in content.lines().enumerate() {
+        let Some(fields) = fields(line) else {
+            continue;
+        };
+        if fields.len() < 2 {
+            return Err(Error::Tab(format!(
+                "Expected at least 2 fields in crypttab entry [{ind}]: '{line}'"
+            )));
+        }
+        let key_file = fields
+            .get(2)
+            .filter(|key| !matches!(key.as_str(), "none" | "-"))
+            .cloned();
+        entries.push(CrypttabEntry {
+            name: fields[0].clone(),
+            device: fields[1].clone(),
+            key_file,
+            options: fields
+                .get(3)
+                .map(|opts| split_options(opts))
+                .unwrap_or_default(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Whitespace separated fields with octal escapes decoded, `None` for blank lines and comments.
+fn fields(line: &str) -> Option<Vec<String>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return None;
+    }
+    Some(trimmed.split_whitespace().map(unescape_octal).collect())
+}
+
+fn split_options(options: &str) -> Vec<String> {
+    options
+        .split(',')
+        .filter(|opt| !opt.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Both the tabs and `/proc/self/mountinfo` escape whitespace as `\040` and the like.
+pub(crate) fn unescape_octal(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut ind = 0;
+    while ind < bytes.len() {
+        if bytes[ind] == b'\\' && ind + 4 <= bytes.len() {
+            let digits = &bytes[ind + 1..ind + 4];
+            if digits.iter().all(|b| (b'0'..=b'7').contains(b)) {
+                let val = digits
+                    .iter()
+                    .fold(0u32, |acc, b| acc * 8 + u32::from(b - b'0'));
+                if let Ok(val) = u8::try_from(val) {
+                    out.push(val);
+                    ind += 4;
+                    continue;
+                }
+            }
+        }
+        out.push(bytes[ind]);
+        ind += 1;
+    }
+    String::from_utf8(out).unwrap_or_else(|_| field.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fstab() {
+        let content = "# /etc/fstab\n\
+        /dev/mapper/croot / ext4 noatime 0 1\n\
+        \n\
+        UUID=1234-ABCD /boot\\040efi vfat defaults 0 2\n\
+        /dev/mapper/cswap none swap sw 0 0\n";
+        let entries = parse_fstab(content).unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!("/dev/mapper/croot", entries[0].spec);
+        assert_eq!("/", entries[0].file);
+        assert_eq!(vec!["noatime".to_string()], entries[0].options);
+        assert_eq!("/boot efi", entries[1].file);
+        assert_eq!(Some("1234-ABCD"), spec_uuid(&entries[1].spec));
+        assert_eq!("swap", entries[2].vfstype);
+    }
+
+    #[test]
+    fn parses_crypttab() {
+        let content = "croot UUID=7e127144-f930-4656-84b4-d6a27fa7caa6 none luks,discard\n\
+        chome /dev/disk/by-uuid/d2059da0-7eae-4396-badf-e6ed0ccfd04b /etc/keys/home.key\n\
+        cswap /dev/nvme0n1p3\n";
+        let entries = parse_crypttab(content).unwrap();
+        assert_eq!(3, entries.len());
+        assert_eq!(None, entries[0].key_file);
+        assert_eq!(
+            vec!["luks".to_string(), "discard".to_string()],
+            entries[0].options
+        );
+        assert_eq!(
+            Some("d2059da0-7eae-4396-badf-e6ed0ccfd04b"),
+            entries[1].uuid()
+        );
+        assert_eq!(Some("/etc/keys/home.key"), entries[1].key_file.as_deref());
+        assert_eq!(None, entries[2].uuid());
+        assert!(parse_crypttab("lonely\n").is_err());
+    }
+}
@@ -0,0 +1,487 @@
+//! Unlocking LUKS volumes without cryptsetup: the keyslot key is derived from the passphrase,
+//! used to decrypt the anti-forensic split key material, the merged key is checked against the
+//! header's digest and then handed to dm-crypt through [`crate::dm`].
+use crate::crypt_opts::{CryptOptions, CryptType};
+use crate::dm::{self, Target};
+use crate::error::{Error, Result};
+use crate::luks::{self, Kdf, Luks1Header, Luks2Header, LuksHeader};
+use crate::sys;
+use aes::cipher::consts::U16;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, BlockSizeUser, KeyInit};
+use aes::{Aes128, Aes192, Aes256};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use sha2::Digest;
+use tiny_std::fs::File;
+use tiny_std::unix::fd::AsRawFd;
+
+const SECTOR_SIZE: usize = 512;
+
+/// Key material, zeroed on drop.
+struct Secret(Vec<u8>);
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.fill(0);
+    }
+}
+
+/// Opens the LUKS volume on `device` as `/dev/mapper/<name>` with `pass`, applying `options` to
+/// the key file and the dm-crypt table. With a detached header the keyslots are read from the
+/// header file and the data offset is taken to be on `device`.
+pub fn open(device: &str, name: &str, pass: &[u8], options: &CryptOptions) -> Result<()> {
+    if options.kind != CryptType::Luks {
+        return Err(Error::Luks(format!(
+            "{device} is {:?}, unlock it with cryptsetup",
+            options.kind
+        )));
+    }
+    let pass = options.key_slice(pass)?;
+    let header_path = options.header.as_deref().unwrap_or(device);
+    let header = luks::read_header(header_path)?;
+    let header_file = File::open(header_path)
+        .map_err(|e| Error::Luks(format!("Failed to open {header_path}: {e}")))?;
+    let file =
+        File::open(device).map_err(|e| Error::Luks(format!("Failed to open {device}: {e}")))?;
+    let device_sectors = device_size(&file, device)? / SECTOR_SIZE as u64;
+    let mut flags: Vec<String> = Vec::new();
+    let mut add_flag = |flag: &str| {
+        if !flags.iter().any(|f| f == flag) {
+            flags.push(String::from(flag));
+        }
+    };
+    if options.allow_discards {
+        add_flag("allow_discards");
+    }
+    for flag in &options.perf {
+        add_flag(flag);
+    }
+    let (key, cipher, offset_sectors, length_sectors) = match &header {
+        LuksHeader::V1(h) => {
+            let key = luks1_volume_key(&header_file, header_path, h, pass, options.key_slot)?;
+            let offset = u64::from(h.payload_offset);
+            let length = device_sectors.checked_sub(offset).ok_or_else(|| {
+                Error::Luks(format!("{device} is smaller than its payload offset"))
+            })?;
+            (
+                key,
+                format!("{}-{}", h.cipher_name, h.cipher_mode),
+                offset,
+                length,
+            )
+        }
+        LuksHeader::V2(h) => {
+            for flag in &h.flags {
+                if let Some(flag) = dm_crypt_flag(flag) {
+                    add_flag(flag);
+                }
+            }
+            let (key, segment) =
+                luks2_volume_key(&header_file, header_path, h, pass, options.key_slot)?;
+            if segment.iv_tweak != 0 {
+                return Err(Error::Luks(format!(
+                    "{device} has a segment with an iv tweak, unlock it with cryptsetup"
+                )));
+            }
+            if segment.sector_size as usize != SECTOR_SIZE {
+                add_flag(&format!("sector_size:{}", segment.sector_size));
+            }
+            let offset = segment.offset / SECTOR_SIZE as u64;
+            let length = match segment.size {
+                Some(size) => size / SECTOR_SIZE as u64,
+                None => device_sectors.checked_sub(offset).ok_or_else(|| {
+                    Error::Luks(format!("{device} is smaller than its data offset"))
+                })?,
+            };
+            // The table length has to be a whole number of encryption sectors
+            let per_sector = u64::from(segment.sector_size) / SECTOR_SIZE as u64;
+            let length = length - length % per_sector.max(1);
+            (key, segment.encryption.clone(), offset, length)
+        }
+    };
+    let key_hex = hex(&key.0);
+    let flags = flags.iter().map(String::as_str).collect::<Vec<_>>();
+    let target = Target::crypt(
+        length_sectors,
+        &cipher,
+        // Hex digits are always utf8
+        core::str::from_utf8(&key_hex.0).unwrap(),
+        device,
+        offset_sectors,
+        &flags,
+    );
+    // Same as cryptsetup, so that its tools recognize the mapping
+    let uuid = format!(
+        "CRYPT-LUKS{}-{}-{name}",
+        header.version(),
+        header.uuid().replace('-', "")
+    );
+    dm::create(name, Some(&uuid), &[target], options.read_only)?;
+    Ok(())
+}
+
+/// LUKS2 persistent flags to dm-crypt optional parameters.
+fn dm_crypt_flag(flag: &str) -> Option<&'static str> {
+    match flag {
+        "allow-discards" => Some("allow_discards"),
+        "same-cpu-crypt" => Some("same_cpu_crypt"),
+        "submit-from-crypt-cpus" => Some("submit_from_crypt_cpus"),
+        "no-read-workqueue" => Some("no_read_workqueue"),
+        "no-write-workqueue" => Some("no_write_workqueue"),
+        _ => None,
+    }
+}
+
+fn luks1_volume_key(
+    file: &File,
+    device: &str,
+    h: &Luks1Header,
+    pass: &[u8],
+    key_slot: Option<u32>,
+) -> Result<Secret> {
+    let hash = Hash::from_name(&h.hash_spec)?;
+    let cipher = format!("{}-{}", h.cipher_name, h.cipher_mode);
+    let key_len = h.key_bytes as usize;
+    let slots = h
+        .keyslots
+        .iter()
+        .filter(|s| s.active && key_slot.is_none_or(|id| id == s.id));
+    for slot in slots {
+        let mut key = Secret(vec![0; key_len]);
+        hash.pbkdf2(pass, &slot.salt, slot.iterations, &mut key.0);
+        let mut material = Secret(vec![0; af_area_len(key_len, slot.stripes)]);
+        read_at(
+            file,
+            device,
+            &mut material.0,
+            u64::from(slot.key_material_offset) * SECTOR_SIZE as u64,
+        )?;
+        decrypt_area(&cipher, &key.0, &mut material.0)?;
+        let candidate = af_merge(&material.0, key_len, slot.stripes, hash);
+        let mut digest = [0u8; 20];
+        hash.pbkdf2(
+            &candidate.0,
+            &h.mk_digest_salt,
+            h.mk_digest_iterations,
+            &mut digest,
+        );
+        if constant_time_eq(&digest, &h.mk_digest) {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::Luks(format!(
+        "No keyslot of {device} could be unlocked with the given key"
+    )))
+}
+
+fn luks2_volume_key<'a>(
+    file: &File,
+    device: &str,
+    h: &'a Luks2Header,
+    pass: &[u8],
+    key_slot: Option<u32>,
+) -> Result<(Secret, &'a luks::Luks2Segment)> {
+    let segment = h
+        .segments
+        .iter()
+        .find(|s| s.kind == "crypt")
+        .ok_or_else(|| Error::Luks(format!("{device} has no crypt segment")))?;
+    // Priority 0 means the slot is only used when asked for explicitly, 2 that it's tried first
+    let mut slots = h
+        .keyslots
+        .iter()
+        .filter(|s| s.kind == "luks2" && key_slot.map_or(s.priority != Some(0), |id| id == s.id))
+        .collect::<Vec<_>>();
+    slots.sort_by_key(|s| Reverse(s.priority.unwrap_or(1)));
+    for slot in slots {
+        let Some(digest) = h
+            .digests
+            .iter()
+            .find(|d| d.keyslots.contains(&slot.id) && d.segments.contains(&segment.id))
+        else {
+            continue;
+        };
+        if digest.kind != "pbkdf2" {
+            return Err(Error::Luks(format!(
+                "{device} has unsupported digest type {}",
+                digest.kind
+            )));
+        }
+        let mut key = Secret(vec![0; slot.area.key_size as usize]);
+        derive_key(&slot.kdf, pass, &mut key.0)?;
+        let key_len = slot.key_size as usize;
+        let mut material = Secret(vec![0; af_area_len(key_len, slot.af.stripes)]);
+        read_at(file, device, &mut material.0, slot.area.offset)?;
+        decrypt_area(&slot.area.encryption, &key.0, &mut material.0)?;
+        let af_hash = Hash::from_name(&slot.af.hash)?;
+        let candidate = af_merge(&material.0, key_len, slot.af.stripes, af_hash);
+        let mut check = vec![0u8; digest.digest.len()];
+        Hash::from_name(&digest.hash)?.pbkdf2(
+            &candidate.0,
+            &digest.salt,
+            digest.iterations,
+            &mut check,
+        );
+        if constant_time_eq(&check, &digest.digest) {
+            return Ok((candidate, segment));
+        }
+    }
+    Err(Error::Luks(format!(
+        "No keyslot of {device} could be unlocked with the given key"
+    )))
+}
+
+fn derive_key(kdf: &Kdf, pass: &[u8], out: &mut [u8]) -> Result<()> {
+    match kdf {
+        Kdf::Pbkdf2 {
+            hash,
+            iterations,
+            salt,
+        } => Hash::from_name(hash)?.pbkdf2(pass, salt, *iterations, out),
+        Kdf::Argon2 {
+            kind,
+            time,
+            memory,
+            cpus,
+            salt,
+        } => {
+            let algorithm = if kind == "argon2i" {
+                argon2::Algorithm::Argon2i
+            } else {
+                argon2::Algorithm::Argon2id
+            };
+            let params = argon2::Params::new(*memory, *time, *cpus, Some(out.len()))
+                .map_err(|e| Error::Luks(format!("Bad {kind} parameters: {e}")))?;
+            argon2::Argon2::new(algorithm, argon2::Version::V0x13, params)
+                .hash_password_into(pass, salt, out)
+                .map_err(|e| Error::Luks(format!("Failed to derive {kind} key: {e}")))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Hash {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sha1" => Ok(Hash::Sha1),
+            "sha256" => Ok(Hash::Sha256),
+            "sha512" => Ok(Hash::Sha512),
+            other => Err(Error::Luks(format!(
+                "Unsupported hash {other}, unlock with cryptsetup"
+            ))),
+        }
+    }
+
+    fn pbkdf2(self, pass: &[u8], salt: &[u8], rounds: u32, out: &mut [u8]) {
+        match self {
+            Hash::Sha1 => pbkdf2::pbkdf2_hmac::<sha1::Sha1>(pass, salt, rounds, out),
+            Hash::Sha256 => pbkdf2::pbkdf2_hmac::<sha2::Sha256>(pass, salt, rounds, out),
+            Hash::Sha512 => pbkdf2::pbkdf2_hmac::<sha2::Sha512>(pass, salt, rounds, out),
+        }
+    }
+
+    fn diffuse(self, block: &mut [u8]) {
+        match self {
+            Hash::Sha1 => diffuse::<sha1::Sha1>(block),
+            Hash::Sha256 => diffuse::<sha2::Sha256>(block),
+            Hash::Sha512 => diffuse::<sha2::Sha512>(block),
+        }
+    }
+}
+
+/// The AF split material padded to whole sectors, as it's laid out on disk.
+fn af_area_len(key_len: usize, stripes: u32) -> usize {
+    (key_len * stripes as usize).div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+}
+
+/// Merges `stripes` blocks of `key_len` from `material` back into the key, see AFsplitter in the
+/// LUKS1 spec.
+fn af_merge(material: &[u8], key_len: usize, stripes: u32, hash: Hash) -> Secret {
+    let mut key = Secret(vec![0; key_len]);
+    let stripes = stripes.max(1) as usize;
+    for (ind, stripe) in material.chunks_exact(key_len).take(stripes).enumerate() {
+        for (k, s) in key.0.iter_mut().zip(stripe) {
+            *k ^= s;
+        }
+        if ind + 1 < stripes {
+            hash.diffuse(&mut key.0);
+        }
+    }
+    key
+}
+
+fn diffuse<D: Digest>(block: &mut [u8]) {
+    let digest_len = <D as Digest>::output_size();
+    for (ind, chunk) in block.chunks_mut(digest_len).enumerate() {
+        let mut hasher = D::new();
+        hasher.update((ind as u32).to_be_bytes());
+        hasher.update(&*chunk);
+        let out = hasher.finalize();
+        chunk.copy_from_slice(&out[..chunk.len()]);
+    }
+}
+
+/// Decrypts keyslot material, sectors are numbered from the start of the area.
+fn decrypt_area(cipher: &str, key: &[u8], area: &mut [u8]) -> Result<()> {
+    match (cipher, key.len()) {
+        ("aes-xts-plain64", 32) => xts_decrypt::<Aes128>(key, area),
+        ("aes-xts-plain64", 64) => xts_decrypt::<Aes256>(key, area),
+        ("aes-cbc-essiv:sha256", 16) => cbc_essiv_decrypt::<Aes128>(key, area),
+        ("aes-cbc-essiv:sha256", 24) => cbc_essiv_decrypt::<Aes192>(key, area),
+        ("aes-cbc-essiv:sha256", 32) => cbc_essiv_decrypt::<Aes256>(key, area),
+        (cipher, len) => {
+            return Err(Error::Luks(format!(
+                "Unsupported keyslot cipher {cipher} with a {len} byte key, unlock with cryptsetup"
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn xts_decrypt<C>(key: &[u8], area: &mut [u8])
+where
+    C: KeyInit + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    let (data_key, tweak_key) = key.split_at(key.len() / 2);
+    // Lengths are checked by the caller
+    let data = C::new_from_slice(data_key).unwrap();
+    let tweak = C::new_from_slice(tweak_key).unwrap();
+    for (sector, chunk) in area.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&(sector as u64).to_le_bytes());
+        tweak.encrypt_block(GenericArray::from_mut_slice(&mut t));
+        for block in chunk.chunks_exact_mut(16) {
+            xor(block, &t);
+            data.decrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &t);
+            // Multiply by the primitive element of GF(2^128)
+            let v = u128::from_le_bytes(t);
+            t = ((v << 1) ^ ((v >> 127) * 0x87)).to_le_bytes();
+        }
+    }
+}
+
+fn cbc_essiv_decrypt<C>(key: &[u8], area: &mut [u8])
+where
+    C: KeyInit + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    let data = C::new_from_slice(key).unwrap();
+    let mut salt = Secret(sha2::Sha256::digest(key).to_vec());
+    let essiv = Aes256::new_from_slice(&salt.0).unwrap();
+    salt.0.fill(0);
+    for (sector, chunk) in area.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+        let mut prev = [0u8; 16];
+        prev[..8].copy_from_slice(&(sector as u64).to_le_bytes());
+        essiv.encrypt_block(GenericArray::from_mut_slice(&mut prev));
+        for block in chunk.chunks_exact_mut(16) {
+            let mut ciphertext = [0u8; 16];
+            ciphertext.copy_from_slice(block);
+            data.decrypt_block(GenericArray::from_mut_slice(block));
+            xor(block, &prev);
+            prev = ciphertext;
+        }
+    }
+}
+
+fn xor(block: &mut [u8], with: &[u8; 16]) {
+    for (b, w) in block.iter_mut().zip(with) {
+        *b ^= w;
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn hex(bytes: &[u8]) -> Secret {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = Secret(Vec::with_capacity(bytes.len() * 2));
+    for b in bytes {
+        out.0.push(DIGITS[usize::from(b >> 4)]);
+        out.0.push(DIGITS[usize::from(b & 0xf)]);
+    }
+    out
+}
+
+fn read_at(file: &File, device: &str, buf: &mut [u8], offset: u64) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = sys::pread(file.as_raw_fd(), &mut buf[read..], offset + read as u64)
+            .map_err(|e| Error::Luks(format!("Failed to read {device} at {offset}: {e:?}")))?;
+        if n == 0 {
+            return Err(Error::Luks(format!(
+                "{device} ended before its keyslot material at {offset}"
+            )));
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+/// Block devices report their size through an ioctl, regular files (images) through stat.
+fn device_size(file: &File, device: &str) -> Result<u64> {
+    let metadata = file
+        .metadata()
+        .map_err(|e| Error::Luks(format!("Failed to stat {device}: {e}")))?;
+    if metadata.is_file() {
+        return Ok(metadata.len());
+    }
+    sys::block_device_size(file.as_raw_fd())
+        .map_err(|e| Error::Luks(format!("Failed to get size of {device}: {e:?}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keyslot_primitives() {
+        // IEEE 1619 XTS-AES-128 vector 1, all zero keys and plaintext in sector 0
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        sector[..32].copy_from_slice(&unhex(
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+        ));
+        decrypt_area("aes-xts-plain64", &[0; 32], &mut sector).unwrap();
+        assert_eq!([0u8; 32], sector[..32]);
+        assert!(decrypt_area("serpent-xts-plain64", &[0; 64], &mut sector).is_err());
+
+        let mut derived = [0u8; 32];
+        Hash::Sha256.pbkdf2(b"password", b"salt", 1, &mut derived);
+        assert_eq!(
+            unhex("120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"),
+            derived
+        );
+
+        // One stripe is the key itself, two are the first diffused and xored into the second
+        let key = [7u8; 32];
+        assert_eq!(key.to_vec(), af_merge(&key, 32, 1, Hash::Sha256).0);
+        let mut split = vec![0u8; 64];
+        split[..32].copy_from_slice(&[1; 32]);
+        let mut diffused = [1u8; 32];
+        Hash::Sha256.diffuse(&mut diffused);
+        for (s, (k, d)) in split[32..].iter_mut().zip(key.iter().zip(diffused)) {
+            *s = k ^ d;
+        }
+        assert_eq!(key.to_vec(), af_merge(&split, 32, 2, Hash::Sha256).0);
+        assert_eq!(512, af_area_len(64, 4));
+        assert_eq!(256_000, af_area_len(64, 4000));
+    }
+}
@@ -0,0 +1,96 @@
+//! zram devices, created through `/sys/class/zram-control` and formatted as swap.
+use crate::cfg::{Zram, ZramSize};
+use crate::error::{Error, Result};
+use crate::swap::{write_swap_header, PAGE_SIZE};
+use crate::sys;
+use alloc::format;
+use alloc::string::{String, ToString};
+use rusl::error::Errno;
+
+/// Creates a zram device sized and compressed as configured, and writes a swap header to it.
+/// Returns the device path.
+pub fn create(zram: &Zram) -> Result<String> {
+    let id = read_trimmed("/sys/class/zram-control/hot_add")?;
+    let dir = format!("/sys/block/zram{id}");
+    // Has to be set before the size
+    if let Some(algorithm) = &zram.algorithm {
+        write(&format!("{dir}/comp_algorithm"), algorithm)?;
+    }
+    let size = match zram.size {
+        ZramSize::Bytes(bytes) => bytes,
+        ZramSize::Percent(percent) => mem_total()? / 100 * u64::from(percent),
+    };
+    let size = size / PAGE_SIZE * PAGE_SIZE;
+    write(&format!("{dir}/disksize"), &size.to_string())?;
+    let device = format!("/dev/zram{id}");
+    ensure_device_node(&device, &dir)?;
+    write_swap_header(&device, size, &zram.name)?;
+    Ok(device)
+}
+
+/// `mdev -s` has already run, devices added after that don't get a node.
+fn ensure_device_node(device: &str, dir: &str) -> Result<()> {
+    match tiny_std::fs::metadata(device) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.matches_errno(Errno::ENOENT) => {}
+        Err(e) => return Err(Error::Swap(format!("Failed to stat {device}: {e}"))),
+    }
+    let major_minor = read_trimmed(&format!("{dir}/dev"))?;
+    let (major, minor) = major_minor
+        .split_once(':')
+        .and_then(|(major, minor)| Some((major.parse::<u64>().ok()?, minor.parse::<u64>().ok()?)))
+        .ok_or_else(|| {
+            Error::Swap(format!(
+                "Malformed device number {major_minor} in {dir}/dev"
+            ))
+        })?;
+    let dev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+    sys::mknod_block(device, dev)
+        .map_err(|e| Error::Swap(format!("Failed to create device node {device}: {e:?}")))
+}
+
+/// `MemTotal` from `/proc/meminfo` in bytes.
+fn mem_total() -> Result<u64> {
+    let meminfo = tiny_std::fs::read_to_string("/proc/meminfo")
+        .map_err(|e| Error::Swap(format!("Failed to read /proc/meminfo: {e}")))?;
+    parse_mem_total(&meminfo).ok_or_else(|| Error::Swap("No MemTotal in /proc/meminfo".to_string()))
+}
+
+fn parse_mem_total(meminfo: &str) -> Option<u64> {
+    let kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    kib.checked_mul(1024)
+}
+
+fn read_trimmed(path: &str) -> Result<String> {
+    tiny_std::fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| {
+            Error::Swap(format!(
+                "Failed to read {path}, zram needs to be built into the kernel: {e}"
+            ))
+        })
+}
+
+fn write(path: &str, value: &str) -> Result<()> {
+    tiny_std::fs::write(path, value.as_bytes())
+        .map_err(|e| Error::Swap(format!("Failed to write {value} to {path}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_mem_total() {
+        let meminfo = "MemTotal:       16318240 kB\nMemFree:         1234567 kB\n";
+        assert_eq!(Some(16318240 * 1024), parse_mem_total(meminfo));
+        assert_eq!(None, parse_mem_total("MemFree: 1 kB\n"));
+    }
+}
@@ -0,0 +1,52 @@
+//! Boots a kernel and a generated initramfs in QEMU against disks matching a cfg,
+//! exiting successfully when the console shows the expected output.
+//! `qemu-boot <kernel> <initramfs dir or cpio> <cfg> <key file> [expected message]`
+use integration_test::qemu::{Boot, Expect, Initramfs, TestCfg};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 4 || args.len() > 5 {
+        eprintln!(
+            "Usage: qemu-boot <kernel> <initramfs dir or cpio> <cfg> <key file> [expected message]"
+        );
+        return ExitCode::FAILURE;
+    }
+    let cfg = match std::fs::read_to_string(&args[2])
+        .map_err(|e| format!("Failed to read cfg at {}: {e}", args[2]))
+        .and_then(|content| TestCfg::parse(&content))
+    {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let key = match std::fs::read(&args[3]) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Failed to read key at {}: {e}", args[3]);
+            return ExitCode::FAILURE;
+        }
+    };
+    let initramfs = PathBuf::from(&args[1]);
+    let initramfs = if initramfs.is_dir() {
+        Initramfs::Dir(initramfs)
+    } else {
+        Initramfs::Archive(initramfs)
+    };
+    let expect = args
+        .get(4)
+        .map_or(Expect::TargetInit, |m| Expect::Message(m.clone()));
+    match Boot::new(PathBuf::from(&args[0]), initramfs, cfg, key).run(&expect) {
+        Ok(transcript) => {
+            println!("{transcript}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! End to end harness for the init binary.
+//!
+//! Builds a fake initramfs root (the same layout as `gen-init.sh`) in a temporary directory and
+//! runs the init binary chrooted into it, inside fresh mount and pid namespaces so that the
+//! binary is pid 1 and can `switch_root` for real without touching the host.
+//! When not running as root a user namespace is added as well, which is enough for everything
+//! that doesn't need block devices. Loop-backed LUKS images can only be set up as root,
+//! the kernel doesn't allow loop, device-mapper or ext4 mounts from a user namespace.
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+pub mod qemu;
+
+/// Printed by the `/sbin/init` of the fake target root, if we see it the switch worked.
+pub const TARGET_INIT_MARKER: &str = "yubi-initramfs-test: reached target init";
+
+/// The init binary under test, defaults to what `build_app.sh` produces.
+pub fn init_binary() -> PathBuf {
+    std::env::var_os("YUBI_INITRAMFS_BIN")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            workspace_root().join("target/x86_64-unknown-linux-gnu/debug/yubi-initramfs")
+        })
+}
+
+/// Needs to be statically linked since it ends up alone in the fake roots.
+pub fn busybox_binary() -> PathBuf {
+    std::env::var_os("YUBI_TEST_BUSYBOX")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| which("busybox"))
+}
+
+pub fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("integration-test is not in a workspace")
+        .to_path_buf()
+}
+
+pub fn is_root() -> bool {
+    let out = run_ok(Command::new("id").arg("-u"));
+    String::from_utf8_lossy(&out.stdout).trim() == "0"
+}
+
+/// Checks that what the test needs is present, returns a reason to skip if not.
+pub fn missing_prerequisites(needs_root: bool) -> Option<String> {
+    if needs_root && !is_root() {
+        return Some("needs root for loop devices and device-mapper".to_string());
+    }
+    if !init_binary().exists() {
+        return Some(format!(
+            "init binary not found at {}, build it with build_app.sh or set YUBI_INITRAMFS_BIN",
+            init_binary().display()
+        ));
+    }
+    let mut bins = vec!["unshare", "chroot", "busybox"];
+    if needs_root {
+        bins.extend(["cryptsetup", "losetup", "mkfs.ext4", "mkswap", "truncate"]);
+    }
+    for bin in bins {
+        if try_which(bin).is_none() {
+            return Some(format!("{bin} not found in PATH"));
+        }
+    }
+    None
+}
+
+pub fn which(bin: &str) -> PathBuf {
+    try_which(bin).unwrap_or_else(|| panic!("Failed to find {bin} in PATH"))
+}
+
+fn try_which(bin: &str) -> Option<PathBuf> {
+    std::env::var_os("PATH").and_then(|path| {
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(bin))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+/// Runs the command and panics with its output if it didn't exit successfully.
+pub fn run_ok(cmd: &mut Command) -> Output {
+    let out = cmd
+        .output()
+        .unwrap_or_else(|e| panic!("Failed to spawn {cmd:?}: {e}"));
+    assert!(
+        out.status.success(),
+        "{cmd:?} exited with {}\nstdout: {}\nstderr: {}",
+        out.status,
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out
+}
+
+/// A scratch directory that is removed on drop.
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("yubi-initramfs-{name}-{}", std::process::id()));
+        if path.exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            eprintln!("Failed to remove work dir {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// A directory tree laid out like the generated initramfs.
+pub struct FakeRoot {
+    dir: PathBuf,
+}
+
+impl FakeRoot {
+    /// Creates the skeleton with busybox and the init binary in it, the same way `gen-init.sh`
+    /// does, except that dynamic dependencies are resolved through `ldd`.
+    pub fn new(dir: PathBuf) -> Self {
+        for sub in [
+            "bin", "dev", "lib64", "mnt/root", "proc", "run", "sbin", "sys",
+        ] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let root = Self { dir };
+        root.install(&busybox_binary(), "bin/busybox");
+        root.install(&init_binary(), "init");
+        root
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn with_cryptsetup(self) -> Self {
+        self.install(&which("cryptsetup"), "sbin/cryptsetup");
+        self
+    }
+
+    pub fn write(&self, rel: &str, content: impl AsRef<[u8]>) {
+        let path = self.dir.join(rel);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&path, content)
+            .unwrap_or_else(|e| panic!("Failed to write {}: {e}", path.display()));
+    }
+
+    pub fn write_cfg(&self, cfg: &str) {
+        self.write("initramfs.cfg", cfg);
+    }
+
+    /// Copies `bin` to `rel` inside the root, along with any shared libraries it links against.
+    pub fn install(&self, bin: &Path, rel: &str) {
+        let dest = self.dir.join(rel);
+        std::fs::copy(bin, &dest).unwrap_or_else(|e| {
+            panic!(
+                "Failed to copy {} to {}: {e}",
+                bin.display(),
+                dest.display()
+            )
+        });
+        for lib in shared_libraries(bin) {
+            let lib_dest = self.dir.join(lib.strip_prefix("/").unwrap());
+            if lib_dest.exists() {
+                continue;
+            }
+            std::fs::create_dir_all(lib_dest.parent().unwrap()).unwrap();
+            std::fs::copy(&lib, &lib_dest)
+                .unwrap_or_else(|e| panic!("Failed to copy library {}: {e}", lib.display()));
+        }
+    }
+}
+
+/// Target root that only contains busybox and an `/sbin/init` printing [`TARGET_INIT_MARKER`].
+pub fn target_root(dir: PathBuf) -> PathBuf {
+    for sub in ["bin", "dev", "home", "proc", "sbin", "sys"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    std::fs::copy(busybox_binary(), dir.join("bin/busybox")).unwrap();
+    let init = dir.join("sbin/init");
+    std::fs::write(
+        &init,
+        format!("#!/bin/busybox sh\necho '{TARGET_INIT_MARKER}'\n"),
+    )
+    .unwrap();
+    run_ok(Command::new("chmod").arg("+x").arg(&init));
+    dir
+}
+
+fn shared_libraries(bin: &Path) -> Vec<PathBuf> {
+    // Static binaries make ldd exit non-zero, which just means there's nothing to copy
+    let Ok(out) = Command::new("ldd").arg(bin).output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| {
+            // libc.so.6 => /lib/x86_64-linux-gnu/libc.so.6 (0x...) or /lib64/ld-linux-x86-64.so.2 (0x...)
+            let path = match line.split_once("=>") {
+                Some((_, rest)) => rest.split_whitespace().next()?,
+                None => line.split_whitespace().next()?,
+            };
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// How a [`FakeRoot`] is run.
+pub struct Run<'a> {
+    root: &'a FakeRoot,
+    args: Vec<String>,
+    mounted_target: Option<PathBuf>,
+}
+
+impl<'a> Run<'a> {
+    pub fn new(root: &'a FakeRoot) -> Self {
+        Self {
+            root,
+            args: Vec::new(),
+            mounted_target: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    /// Mounts a tmpfs on `/mnt/root` populated from `dir` before starting the init,
+    /// standing in for the user filesystems when there's no block devices to mount.
+    pub fn mounted_target(mut self, dir: PathBuf) -> Self {
+        self.mounted_target = Some(dir);
+        self
+    }
+
+    /// Runs the init as pid 1 of a new pid namespace, chrooted into a tmpfs copy of the fake root.
+    /// A tmpfs since `switch_root` refuses to run from anything else.
+    pub fn run(self, work: &Path) -> Output {
+        let new_root = work.join("rootfs");
+        std::fs::create_dir_all(&new_root).unwrap();
+        let mut script = String::from("set -e\n");
+        let _ = writeln!(
+            script,
+            "mount -t tmpfs -o mode=0755 none '{root}'\ncp -a '{staging}/.' '{root}'",
+            root = new_root.display(),
+            staging = self.root.path().display()
+        );
+        if let Some(target) = &self.mounted_target {
+            let _ = writeln!(
+                script,
+                "mount -t tmpfs -o mode=0755 none '{root}/mnt/root'\ncp -a '{target}/.' '{root}/mnt/root'",
+                root = new_root.display(),
+                target = target.display()
+            );
+        }
+        let _ = write!(script, "cd '{}'\nexec chroot . /init", new_root.display());
+        for arg in &self.args {
+            let _ = write!(script, " '{arg}'");
+        }
+        let mut cmd = Command::new("unshare");
+        cmd.args(["--mount", "--propagation", "private", "--pid", "--fork"]);
+        if !is_root() {
+            // Mounting sysfs additionally needs a network namespace owned by the user namespace
+            cmd.args(["--user", "--map-root-user", "--net"]);
+        }
+        cmd.arg("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to spawn unshare")
+    }
+}
+
+/// Combined stdout and stderr, for asserting on.
+pub fn output_text(out: &Output) -> String {
+    format!(
+        "{}{}",
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    )
+}
+
+/// What goes inside a [`LuksImage`].
+pub enum Content<'a> {
+    /// An ext4 filesystem populated from the directory.
+    Ext4(&'a Path),
+    Swap,
+}
+
+/// A file-backed LUKS2 volume attached to a loop device, needs root.
+pub struct LuksImage {
+    file: PathBuf,
+    loop_device: String,
+    uuid: String,
+}
+
+impl LuksImage {
+    pub fn create(
+        work: &Path,
+        name: &str,
+        size_mb: u32,
+        key_file: &Path,
+        content: Content,
+    ) -> Self {
+        Self::create_with_uuid(work, name, size_mb, key_file, content, None)
+    }
+
+    /// Formats with cheap pbkdf2 parameters, the KDF is not what's under test.
+    /// A fixed `uuid` is for matching a cfg that's already baked into an image.
+    pub fn create_with_uuid(
+        work: &Path,
+        name: &str,
+        size_mb: u32,
+        key_file: &Path,
+        content: Content,
+        uuid: Option<&str>,
+    ) -> Self {
+        let file = work.join(format!("{name}.img"));
+        run_ok(
+            Command::new("truncate")
+                .arg("-s")
+                .arg(format!("{size_mb}M"))
+                .arg(&file),
+        );
+        let out = run_ok(
+            Command::new("losetup")
+                .arg("--find")
+                .arg("--show")
+                .arg(&file),
+        );
+        let loop_device = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        let mut image = Self {
+            file,
+            loop_device,
+            uuid: String::new(),
+        };
+        let mut format = Command::new("cryptsetup");
+        format
+            .args(["luksFormat", "--batch-mode", "--type", "luks2"])
+            .args(["--pbkdf", "pbkdf2", "--pbkdf-force-iterations", "1000"])
+            .arg("--key-file")
+            .arg(key_file);
+        if let Some(uuid) = uuid {
+            format.arg("--uuid").arg(uuid);
+        }
+        run_ok(format.arg(&image.loop_device));
+        let out = run_ok(
+            Command::new("cryptsetup")
+                .arg("luksUUID")
+                .arg(&image.loop_device),
+        );
+        image.uuid = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        let mapper = format!("yubi-initramfs-test-{}-{name}", std::process::id());
+        run_ok(
+            Command::new("cryptsetup")
+                .arg("open")
+                .arg("--key-file")
+                .arg(key_file)
+                .arg(&image.loop_device)
+                .arg(&mapper),
+        );
+        let mapped = format!("/dev/mapper/{mapper}");
+        let res = match content {
+            Content::Ext4(dir) => Command::new("mkfs.ext4")
+                .arg("-q")
+                .arg("-d")
+                .arg(dir)
+                .arg(&mapped)
+                .output(),
+            Content::Swap => Command::new("mkswap").arg(&mapped).output(),
+        };
+        run_ok(Command::new("cryptsetup").arg("close").arg(&mapper));
+        let res = res.expect("Failed to spawn mkfs");
+        assert!(res.status.success(), "Failed to create filesystem: {res:?}");
+        image
+    }
+
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// The backing file, stays around after the loop device is detached.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+}
+
+impl Drop for LuksImage {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup")
+            .arg("-d")
+            .arg(&self.loop_device)
+            .status();
+    }
+}
+
+/// Mappings the init creates are global even though the mounts die with the namespace,
+/// swap is turned off and the mappings closed on drop. Refuses to start if any of them
+/// already exist so that we never close the host's own volumes.
+/// Holds a process wide lock since tests run in parallel and the names are fixed by the cfg.
+pub struct MapperGuard {
+    names: Vec<&'static str>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+static MAPPERS: Mutex<()> = Mutex::new(());
+
+impl MapperGuard {
+    pub fn new(names: &[&'static str]) -> Result<Self, String> {
+        // A test panicking while holding it is no reason to fail the others
+        let lock = MAPPERS.lock().unwrap_or_else(PoisonError::into_inner);
+        for name in names {
+            if Path::new("/dev/mapper").join(name).exists() {
+                return Err(format!("/dev/mapper/{name} already exists on this host"));
+            }
+        }
+        Ok(Self {
+            names: names.to_vec(),
+            _lock: lock,
+        })
+    }
+}
+
+impl Drop for MapperGuard {
+    fn drop(&mut self) {
+        for name in &self.names {
+            let dev = Path::new("/dev/mapper").join(name);
+            if !dev.exists() {
+                continue;
+            }
+            let _ = Command::new("swapoff")
+                .arg(&dev)
+                .stderr(Stdio::null())
+                .status();
+            // The namespace's mounts are released lazily, give it a few tries
+            for _ in 0..10 {
+                let closed = Command::new("cryptsetup")
+                    .arg("close")
+                    .arg(name)
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|s| s.success());
+                if closed {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(200));
+            }
+        }
+    }
+}
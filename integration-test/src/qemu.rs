@@ -0,0 +1,259 @@
+//! Boots a kernel with a generated initramfs in QEMU.
+//!
+//! Disks are built to match a test cfg (LUKS2 volumes formatted with the cfg's UUIDs), the guest
+//! runs under TCG so no KVM is needed, and everything is asserted on from the serial console.
+//! Building the disks needs root, QEMU itself doesn't.
+//! The kernel needs virtio-blk, dm-crypt, ext4 and a serial console built in.
+use crate::{run_ok, target_root, Content, LuksImage, WorkDir, TARGET_INIT_MARKER};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// The parts of an `initramfs.cfg` the disks need to match.
+#[derive(Debug, Clone)]
+pub struct TestCfg {
+    pub root: String,
+    pub swap: String,
+    pub home: String,
+    pub key_file_path: String,
+}
+
+impl TestCfg {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut root = None;
+        let mut swap = None;
+        let mut home = None;
+        let mut key_file_path = None;
+        for line in content.lines().map(str::trim) {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = Some(value.to_string());
+            match key {
+                "root" => root = value,
+                "swap" => swap = value,
+                "home" => home = value,
+                "key_file_path" => key_file_path = value,
+                _ => {}
+            }
+        }
+        Ok(Self {
+            root: root.ok_or("No root in test cfg")?,
+            swap: swap.ok_or("No swap in test cfg")?,
+            home: home.ok_or("No home in test cfg")?,
+            key_file_path: key_file_path.ok_or("No key_file_path in test cfg")?,
+        })
+    }
+
+    pub fn to_cfg_string(&self) -> String {
+        format!(
+            "root={}\nswap={}\nhome={}\nkey_file_path={}\n",
+            self.root, self.swap, self.home, self.key_file_path
+        )
+    }
+}
+
+/// The initramfs to boot.
+pub enum Initramfs {
+    /// A directory laid out like `gen-init.sh` produces it, the test cfg and key are written into
+    /// a copy of it which is then packed.
+    Dir(PathBuf),
+    /// An already packed cpio archive, which has to contain a cfg matching the [`TestCfg`].
+    Archive(PathBuf),
+}
+
+/// What the serial console should show before the guest is considered done.
+pub enum Expect {
+    TargetInit,
+    Message(String),
+}
+
+impl Expect {
+    fn needle(&self) -> &str {
+        match self {
+            Expect::TargetInit => TARGET_INIT_MARKER,
+            Expect::Message(m) => m,
+        }
+    }
+}
+
+pub struct Boot {
+    kernel: PathBuf,
+    initramfs: Initramfs,
+    cfg: TestCfg,
+    key: Vec<u8>,
+    initramfs_key: Option<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl Boot {
+    /// `key` is what the disks are formatted with, and what's packed into a [`Initramfs::Dir`].
+    pub fn new(kernel: PathBuf, initramfs: Initramfs, cfg: TestCfg, key: Vec<u8>) -> Self {
+        Self {
+            kernel,
+            initramfs,
+            cfg,
+            key,
+            initramfs_key: None,
+            // TCG is slow, especially if cryptsetup decides to benchmark
+            timeout: Duration::from_secs(300),
+        }
+    }
+
+    /// Pack a different key than the disks were formatted with, only for [`Initramfs::Dir`].
+    pub fn initramfs_key(mut self, key: Vec<u8>) -> Self {
+        self.initramfs_key = Some(key);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Boots and waits for the expected console output, returning the transcript either way.
+    pub fn run(&self, expect: &Expect) -> Result<String, String> {
+        let work = WorkDir::new("qemu");
+        let initramfs = match &self.initramfs {
+            Initramfs::Dir(dir) => self.pack(work.path(), dir)?,
+            Initramfs::Archive(archive) => {
+                if self.initramfs_key.is_some() {
+                    return Err("Can't replace the key of a packed initramfs".to_string());
+                }
+                archive.clone()
+            }
+        };
+        let key_file = work.path().join("disk.key");
+        std::fs::write(&key_file, &self.key).map_err(|e| format!("Failed to write key: {e}"))?;
+        let target = target_root(work.path().join("target"));
+        let disks = [
+            LuksImage::create_with_uuid(
+                work.path(),
+                "root",
+                64,
+                &key_file,
+                Content::Ext4(&target),
+                Some(&self.cfg.root),
+            ),
+            LuksImage::create_with_uuid(
+                work.path(),
+                "swap",
+                32,
+                &key_file,
+                Content::Swap,
+                Some(&self.cfg.swap),
+            ),
+            LuksImage::create_with_uuid(
+                work.path(),
+                "home",
+                32,
+                &key_file,
+                Content::Ext4(&target_root(work.path().join("home"))),
+                Some(&self.cfg.home),
+            ),
+        ];
+        self.boot(&initramfs, &disks, expect)
+    }
+
+    fn pack(&self, work: &Path, dir: &Path) -> Result<PathBuf, String> {
+        let staging = work.join("initramfs");
+        run_ok(Command::new("cp").arg("-a").arg(dir).arg(&staging));
+        std::fs::write(staging.join("initramfs.cfg"), self.cfg.to_cfg_string())
+            .map_err(|e| format!("Failed to write cfg: {e}"))?;
+        let key = self.initramfs_key.as_ref().unwrap_or(&self.key);
+        let key_path = staging.join(self.cfg.key_file_path.trim_start_matches('/'));
+        std::fs::write(&key_path, key)
+            .map_err(|e| format!("Failed to write key to {}: {e}", key_path.display()))?;
+        let archive = work.join("initramfs.cpio");
+        run_ok(
+            Command::new("sh")
+                .arg("-c")
+                .arg("cd \"$1\" && find . | cpio -o -H newc --quiet > \"$2\"")
+                .arg("sh")
+                .arg(&staging)
+                .arg(&archive),
+        );
+        Ok(archive)
+    }
+
+    fn boot(
+        &self,
+        initramfs: &Path,
+        disks: &[LuksImage],
+        expect: &Expect,
+    ) -> Result<String, String> {
+        let qemu =
+            std::env::var("YUBI_TEST_QEMU").unwrap_or_else(|_| "qemu-system-x86_64".to_string());
+        let mut cmd = Command::new(&qemu);
+        cmd.args(["-accel", "tcg", "-m", "512", "-smp", "1"])
+            .args([
+                "-display",
+                "none",
+                "-serial",
+                "stdio",
+                "-monitor",
+                "none",
+                "-no-reboot",
+            ])
+            .arg("-kernel")
+            .arg(&self.kernel)
+            .arg("-initrd")
+            .arg(initramfs)
+            .args(["-append", "console=ttyS0 panic=-1 rdinit=/init"]);
+        for disk in disks {
+            cmd.arg("-drive").arg(format!(
+                "file={},format=raw,if=virtio",
+                disk.file().display()
+            ));
+        }
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("Failed to spawn {qemu}: {e}"))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (send, recv) = channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if send.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let deadline = Instant::now() + self.timeout;
+        let mut transcript = String::new();
+        let res = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match recv.recv_timeout(left) {
+                Ok(line) => {
+                    transcript.push_str(&line);
+                    transcript.push('\n');
+                    if line.contains(expect.needle()) {
+                        break Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    break Err(format!("Timed out after {:?}", self.timeout))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err("Guest exited".to_string());
+                }
+            }
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        match res {
+            Ok(()) => Ok(transcript),
+            Err(e) => Err(format!(
+                "{e} before seeing '{}', console:\n{transcript}",
+                expect.needle()
+            )),
+        }
+    }
+}
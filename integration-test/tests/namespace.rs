@@ -0,0 +1,118 @@
+//! Runs the built init binary inside namespaces, see the crate docs.
+//! Ignored by default since they need the binary built through `build_app.sh` and a static busybox,
+//! run with `cargo test -p integration-test -- --ignored`, as root to include the LUKS tests.
+use integration_test::{
+    missing_prerequisites, output_text, target_root, Content, FakeRoot, LuksImage, MapperGuard,
+    Run, WorkDir, TARGET_INIT_MARKER,
+};
+
+const KEY: &[u8] = b"integration-test-key";
+
+macro_rules! skip_unless_ready {
+    ($needs_root:expr) => {
+        if let Some(reason) = missing_prerequisites($needs_root) {
+            eprintln!("Skipping: {reason}");
+            return;
+        }
+    };
+}
+
+#[test]
+#[ignore]
+fn switch_root_reaches_target_init() {
+    skip_unless_ready!(false);
+    let work = WorkDir::new("switch");
+    let root = FakeRoot::new(work.path().join("staging"));
+    // Never used by the switch, but the cfg is parsed before the command is
+    root.write_cfg(
+        "root=7e127144-f930-4656-84b4-d6a27fa7caa6\n\
+         swap=c1c1f5cf-f248-4269-82d3-99d1a6004e1e\n\
+         home=d2059da0-7eae-4396-badf-e6ed0ccfd04b\n\
+         key_file_path=/crypto.key\n",
+    );
+    let target = target_root(work.path().join("target"));
+    let out = Run::new(&root)
+        .arg("initramfs.cfg")
+        .arg("--switch")
+        .mounted_target(target)
+        .run(work.path());
+    let text = output_text(&out);
+    assert!(text.contains(TARGET_INIT_MARKER), "{text}");
+}
+
+struct Volumes {
+    _guard: MapperGuard,
+    root: LuksImage,
+    swap: LuksImage,
+    home: LuksImage,
+}
+
+fn setup_volumes(work: &WorkDir, key: &[u8]) -> Result<Volumes, String> {
+    let guard = MapperGuard::new(&["croot", "cswap", "chome"])?;
+    let key_file = work.path().join("image.key");
+    std::fs::write(&key_file, key).unwrap();
+    let target = target_root(work.path().join("target"));
+    Ok(Volumes {
+        _guard: guard,
+        root: LuksImage::create(work.path(), "root", 64, &key_file, Content::Ext4(&target)),
+        swap: LuksImage::create(work.path(), "swap", 32, &key_file, Content::Swap),
+        home: LuksImage::create(
+            work.path(),
+            "home",
+            32,
+            &key_file,
+            Content::Ext4(&target_root(work.path().join("home"))),
+        ),
+    })
+}
+
+fn luks_root(work: &WorkDir, volumes: &Volumes, key: &[u8]) -> FakeRoot {
+    let root = FakeRoot::new(work.path().join("staging")).with_cryptsetup();
+    root.write("crypto.key", key);
+    root.write_cfg(&format!(
+        "root={}\nswap={}\nhome={}\nkey_file_path=/crypto.key\n",
+        volumes.root.uuid(),
+        volumes.swap.uuid(),
+        volumes.home.uuid()
+    ));
+    root
+}
+
+#[test]
+#[ignore]
+fn full_init_unlocks_mounts_and_switches_root() {
+    skip_unless_ready!(true);
+    let work = WorkDir::new("full");
+    let volumes = match setup_volumes(&work, KEY) {
+        Ok(v) => v,
+        Err(reason) => {
+            eprintln!("Skipping: {reason}");
+            return;
+        }
+    };
+    let root = luks_root(&work, &volumes, KEY);
+    let out = Run::new(&root).run(work.path());
+    let text = output_text(&out);
+    assert!(text.contains("Done, switching root"), "{text}");
+    assert!(text.contains(TARGET_INIT_MARKER), "{text}");
+}
+
+#[test]
+#[ignore]
+fn wrong_key_bails_to_shell() {
+    skip_unless_ready!(true);
+    let work = WorkDir::new("wrong-key");
+    let volumes = match setup_volumes(&work, KEY) {
+        Ok(v) => v,
+        Err(reason) => {
+            eprintln!("Skipping: {reason}");
+            return;
+        }
+    };
+    let root = luks_root(&work, &volumes, b"not-the-key");
+    let out = Run::new(&root).run(work.path());
+    let text = output_text(&out);
+    assert!(text.contains("Failed to decrypt root partition"), "{text}");
+    assert!(text.contains("Bailing to shell"), "{text}");
+    assert!(!text.contains(TARGET_INIT_MARKER), "{text}");
+}
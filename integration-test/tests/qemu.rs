@@ -0,0 +1,70 @@
+//! Boots the generated initramfs in QEMU, see `integration_test::qemu`.
+//! Needs `YUBI_TEST_KERNEL` and `YUBI_TEST_INITRAMFS` (the `gen-init.sh` output directory,
+//! or a packed cpio together with `YUBI_TEST_CFG` and `YUBI_TEST_KEY`), and root for the disks.
+//! `cargo test -p integration-test --test qemu -- --ignored`
+use integration_test::is_root;
+use integration_test::qemu::{Boot, Expect, Initramfs, TestCfg};
+use std::path::PathBuf;
+
+const KEY: &[u8] = b"qemu-test-key";
+
+fn boot() -> Option<Boot> {
+    let Some(kernel) = std::env::var_os("YUBI_TEST_KERNEL").map(PathBuf::from) else {
+        eprintln!("Skipping: YUBI_TEST_KERNEL not set");
+        return None;
+    };
+    let Some(initramfs) = std::env::var_os("YUBI_TEST_INITRAMFS").map(PathBuf::from) else {
+        eprintln!("Skipping: YUBI_TEST_INITRAMFS not set");
+        return None;
+    };
+    if !is_root() {
+        eprintln!("Skipping: needs root to build the disks");
+        return None;
+    }
+    if initramfs.is_dir() {
+        let cfg = TestCfg {
+            root: "7e127144-f930-4656-84b4-d6a27fa7caa6".to_string(),
+            swap: "c1c1f5cf-f248-4269-82d3-99d1a6004e1e".to_string(),
+            home: "d2059da0-7eae-4396-badf-e6ed0ccfd04b".to_string(),
+            key_file_path: "/crypto.key".to_string(),
+        };
+        return Some(Boot::new(
+            kernel,
+            Initramfs::Dir(initramfs),
+            cfg,
+            KEY.to_vec(),
+        ));
+    }
+    let cfg = std::env::var("YUBI_TEST_CFG").expect("YUBI_TEST_CFG needed with a packed initramfs");
+    let key = std::env::var("YUBI_TEST_KEY").expect("YUBI_TEST_KEY needed with a packed initramfs");
+    let cfg = TestCfg::parse(&std::fs::read_to_string(cfg).unwrap()).unwrap();
+    let key = std::fs::read(key).unwrap();
+    Some(Boot::new(kernel, Initramfs::Archive(initramfs), cfg, key))
+}
+
+#[test]
+#[ignore]
+fn boots_to_target_init() {
+    let Some(boot) = boot() else {
+        return;
+    };
+    if let Err(e) = boot.run(&Expect::TargetInit) {
+        panic!("{e}");
+    }
+}
+
+#[test]
+#[ignore]
+fn wrong_key_bails_to_shell() {
+    let Some(boot) = boot() else {
+        return;
+    };
+    if std::env::var_os("YUBI_TEST_CFG").is_some() {
+        eprintln!("Skipping: can't swap the key in a packed initramfs");
+        return;
+    }
+    let boot = boot.initramfs_key(b"not-the-key".to_vec());
+    if let Err(e) = boot.run(&Expect::Message("Bailing to shell".to_string())) {
+        panic!("{e}");
+    }
+}
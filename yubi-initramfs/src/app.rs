@@ -1,13 +1,16 @@
+use alloc::string::String;
+use alloc::vec;
+use initramfs_lib::crypt_opts::parse_crypt_options;
+use initramfs_lib::doctor::Status;
 use initramfs_lib::{bail_to_shell, read_cfg, Cfg};
-use unix_print::{unix_eprintln, unix_println};
+use unix_print::{unix_eprintln, unix_print, unix_println};
 
 /// Some references [Gentoo custom initramfs](https://wiki.gentoo.org/wiki/Custom_Initramfs)
 /// [Boot kernel without bootloader](https://tecporto.pt/wiki/index.php/Booting_the_Linux_Kernel_without_a_bootloader)
 pub(crate) fn main_loop() -> Result<(), i32> {
     let mut args = tiny_std::env::args();
     let _self = args.next();
-    let cfg_path = args.next();
-    if cfg_path.is_none() {
+    let Some(cfg_path) = args.next() else {
         unix_println!("Invoked without arguments, assuming running as init.");
         let cfg = read_cfg("initramfs.cfg").map_err(|e| {
             unix_eprintln!(
@@ -16,18 +19,12 @@ pub(crate) fn main_loop() -> Result<(), i32> {
             1
         })?;
         return run_init(&cfg);
-    }
+    };
 
-    let cfg_path = args
-        .next()
-        .ok_or_else(|| {
-            unix_eprintln!("No cfg path supplied, required as first argument");
-            1
-        })?
-        .map_err(|e| {
-            unix_eprintln!("First arg not parseable as utf8: {e}");
-            1
-        })?;
+    let cfg_path = cfg_path.map_err(|e| {
+        unix_eprintln!("First arg not parseable as utf8: {e}");
+        1
+    })?;
     let command = args
         .next()
         .ok_or_else(|| {
@@ -38,6 +35,10 @@ pub(crate) fn main_loop() -> Result<(), i32> {
             unix_eprintln!("Command arg not parseable as utf8: {e}");
             1
         })?;
+    if matches!(command, "--generate-config" | "-g") {
+        // The cfg path is where the generated cfg goes
+        return generate_config(cfg_path, args.next());
+    }
     let cfg = read_cfg(cfg_path).map_err(|e| {
         unix_eprintln!("Failed to read cfg: {e:?}");
         1
@@ -54,12 +55,49 @@ pub(crate) fn main_loop() -> Result<(), i32> {
                 unix_eprintln!("Error: Failed to get partitions: {e:?}");
                 1
             })?;
-            unix_println!(
-                "Successfully found partitions.\nRoot: {}\nSwap: {}\nHome: {}",
-                partitions.root,
-                partitions.swap,
-                partitions.home
-            );
+            unix_println!("Successfully found partitions.");
+            for part in partitions {
+                unix_println!("{}: {} ({})", part.name, part.device, part.uuid);
+            }
+            Ok(())
+        }
+        "--luks-dump" | "-k" => {
+            let devices = if let Some(device) = args.next() {
+                let device = device.map_err(|e| {
+                    unix_eprintln!("Device arg not parseable as utf8: {e}");
+                    1
+                })?;
+                vec![String::from(device)]
+            } else {
+                initramfs_lib::get_partitions(&cfg)
+                    .map_err(|e| {
+                        unix_eprintln!("Error: Failed to get partitions: {e:?}");
+                        1
+                    })?
+                    .into_iter()
+                    .map(|part| part.device)
+                    .collect()
+            };
+            for device in devices {
+                let header = initramfs_lib::luks::read_header(&device).map_err(|e| {
+                    unix_eprintln!("Error: Failed to read LUKS header of {device}: {e:?}");
+                    1
+                })?;
+                unix_println!("{device}:\n{header}");
+            }
+            Ok(())
+        }
+        "--headers" | "-H" => {
+            // Detached headers, also the ones set in an imported crypttab, for gen-init.sh to copy
+            for crypt in &cfg.crypt {
+                let options = parse_crypt_options(&crypt.options).map_err(|e| {
+                    unix_eprintln!("Error: Invalid options for {}: {e:?}", crypt.name);
+                    1
+                })?;
+                if let Some(header) = options.header {
+                    unix_println!("{header}");
+                }
+            }
             Ok(())
         }
         "--mount-pseudo" | "-p" => {
@@ -93,6 +131,25 @@ pub(crate) fn main_loop() -> Result<(), i32> {
             unix_eprintln!("Error: Failed to switch root {err:?}");
             Err(1)
         }
+        "--doctor" | "-d" => {
+            let mut failed = false;
+            for finding in initramfs_lib::doctor::doctor(&cfg) {
+                let status = match finding.status {
+                    Status::Ok => "ok",
+                    Status::Warn => "warn",
+                    Status::Fail => {
+                        failed = true;
+                        "FAIL"
+                    }
+                };
+                unix_println!("[{status:>4}] {}: {}", finding.check, finding.detail);
+            }
+            if failed {
+                unix_eprintln!("Error: cfg at path {cfg_path} doesn't match this host");
+                return Err(1);
+            }
+            Ok(())
+        }
         "--init" => run_init(&cfg),
         s => {
             unix_eprintln!("Unrecognized argument {s}");
@@ -101,6 +158,30 @@ pub(crate) fn main_loop() -> Result<(), i32> {
     }
 }
 
+fn generate_config(
+    out_path: &str,
+    key_file_path: Option<Result<&str, core::str::Utf8Error>>,
+) -> Result<(), i32> {
+    let key_file_path = key_file_path.transpose().map_err(|e| {
+        unix_eprintln!("Key file arg not parseable as utf8: {e}");
+        1
+    })?;
+    let cfg = initramfs_lib::generate::generate_cfg(key_file_path).map_err(|e| {
+        unix_eprintln!("Error: Failed to generate cfg: {e:?}");
+        1
+    })?;
+    if out_path == "-" {
+        unix_print!("{cfg}");
+        return Ok(());
+    }
+    tiny_std::fs::write(out_path, cfg.as_bytes()).map_err(|e| {
+        unix_eprintln!("Error: Failed to write generated cfg to {out_path}: {e}");
+        1
+    })?;
+    unix_println!("Wrote generated cfg to {out_path}, check it with --doctor.");
+    Ok(())
+}
+
 fn run_init(cfg: &Cfg) -> Result<(), i32> {
     if let Err(e) = initramfs_lib::full_init(cfg) {
         unix_eprintln!("Error: Failed init full {e:?}");
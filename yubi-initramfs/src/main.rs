@@ -9,18 +9,28 @@ extern crate alloc;
 
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
+#[cfg(feature = "threaded")]
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use dlmalloc::Dlmalloc;
 use tiny_std::process::exit;
+#[cfg(not(feature = "threaded"))]
 use unix_print::unix_eprintln;
 
+#[cfg(not(feature = "threaded"))]
 #[global_allocator]
 static ALLOCATOR: SingleThreadedAlloc = SingleThreadedAlloc::new();
 
+#[cfg(feature = "threaded")]
+#[global_allocator]
+static ALLOCATOR: LockedAlloc = LockedAlloc::new();
+
+#[cfg(not(feature = "threaded"))]
 struct SingleThreadedAlloc {
     inner: UnsafeCell<Dlmalloc>,
 }
 
+#[cfg(not(feature = "threaded"))]
 impl SingleThreadedAlloc {
     pub(crate) const fn new() -> Self {
         SingleThreadedAlloc {
@@ -29,6 +39,7 @@ impl SingleThreadedAlloc {
     }
 }
 
+#[cfg(not(feature = "threaded"))]
 unsafe impl GlobalAlloc for SingleThreadedAlloc {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -52,10 +63,83 @@ unsafe impl GlobalAlloc for SingleThreadedAlloc {
 }
 
 /// Extremely unsafe, this program is not thread safe at all will immediately segfault on more threads
+#[cfg(not(feature = "threaded"))]
 unsafe impl Sync for SingleThreadedAlloc {}
 
+#[cfg(not(feature = "threaded"))]
 unsafe impl Send for SingleThreadedAlloc {}
 
+/// Dlmalloc behind a spinlock, for when the init runs threads. Allocations are short enough that
+/// spinning beats sleeping on a futex.
+#[cfg(feature = "threaded")]
+struct LockedAlloc {
+    locked: AtomicBool,
+    inner: UnsafeCell<Dlmalloc>,
+}
+
+#[cfg(feature = "threaded")]
+impl LockedAlloc {
+    pub(crate) const fn new() -> Self {
+        LockedAlloc {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(Dlmalloc::new()),
+        }
+    }
+
+    #[inline]
+    fn with_lock<R>(&self, func: impl FnOnce(&mut Dlmalloc) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        // Safety: Only the lock holder gets here
+        let res = func(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+        res
+    }
+}
+
+#[cfg(feature = "threaded")]
+unsafe impl GlobalAlloc for LockedAlloc {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_lock(|inner| inner.malloc(layout.size(), layout.align()))
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_lock(|inner| inner.free(ptr, layout.size(), layout.align()))
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.with_lock(|inner| inner.calloc(layout.size(), layout.align()))
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.with_lock(|inner| inner.realloc(ptr, layout.size(), layout.align(), new_size))
+    }
+}
+
+/// The inner allocator is only reached through the lock
+#[cfg(feature = "threaded")]
+unsafe impl Sync for LockedAlloc {}
+
+/// With `threaded` the panic handler is tiny-std's thread aware one, defined next to
+/// `thread::spawn` in a module that's only compiled with its `threaded` and `symbols` features
+/// (`tiny-std/src/thread/spawn.rs`). Naming `spawn` fails the build here if that module is gone.
+#[cfg(feature = "threaded")]
+const _: () = {
+    let _ = tiny_std::thread::spawn::<(), fn()>;
+};
+
+#[cfg(not(feature = "threaded"))]
 #[panic_handler]
 fn on_panic(info: &core::panic::PanicInfo) -> ! {
     unix_eprintln!("{info}");
@@ -78,4 +162,4 @@ fn main() -> i32 {
     } else {
         0
     }
-}
\ No newline at end of file
+}